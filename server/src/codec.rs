@@ -0,0 +1,697 @@
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, OnceLock};
+
+use borsh::{BorshDeserialize as _, BorshSerialize as _};
+
+use crate::frame::{
+    ClientFrame, ClientFrameType, DecodeError, EncodeError, ErrorCode, ServerFrame, DEFAULT_ROOM,
+    MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+
+/// (De)serializes frames according to the protocol version spoken on a connection.
+///
+/// Every connection starts out speaking [`MIN_PROTOCOL_VERSION`], which is also what the
+/// [`ClientFrameType::Hello`](crate::frame::ClientFrameType::Hello) and
+/// [`ServerFrame::Welcome`] are encoded with. Right after the `Welcome` frame, both sides switch to
+/// the version it names. Clones share that state, so the stream and sink of a connection switch
+/// together.
+///
+/// Frames are either borsh or JSON, and the first one decoded sets the [`Format`] for the rest of
+/// the connection. JSON is newer than version 1, so connections that settle on it start out
+/// speaking [`PROTOCOL_VERSION`] instead.
+#[derive(Debug, Clone)]
+pub struct Codec {
+    version: Arc<AtomicU16>,
+    format: Arc<OnceLock<Format>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Borsh,
+    /// Meant for debugging and lightweight clients that would rather not implement borsh.
+    Json,
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Self {
+            version: Arc::new(AtomicU16::new(MIN_PROTOCOL_VERSION)),
+            format: Arc::default(),
+        }
+    }
+}
+
+impl Codec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn version(&self) -> u16 {
+        self.version.load(Ordering::Acquire)
+    }
+
+    /// The format frames are sent in, if it's been settled yet.
+    pub fn format(&self) -> Option<Format> {
+        self.format.get().copied()
+    }
+
+    fn settle_format(&self, format: Format) -> Result<(), DecodeError> {
+        let mut settled = false;
+        let current = *self.format.get_or_init(|| {
+            settled = true;
+            format
+        });
+        if current != format {
+            return Err(DecodeError::MixedFormats);
+        }
+        // a hello, if that's what is being decoded, still gets to pick an older version
+        if settled && format == Format::Json {
+            self.version.store(PROTOCOL_VERSION, Ordering::Release);
+        }
+        Ok(())
+    }
+
+    fn switch_after(&self, frame: &ServerFrame) {
+        if let ServerFrame::Welcome {
+            protocol_version, ..
+        } = frame
+        {
+            self.version.store(*protocol_version, Ordering::Release);
+        }
+    }
+
+    pub fn decode_client_frame(&self, bytes: &[u8]) -> Result<ClientFrame, DecodeError> {
+        self.settle_format(Format::Borsh)?;
+        match self.version() {
+            1 => ClientFrame::<u8>::try_from_slice(bytes).map(|f| f.map_id(Into::into)),
+            _ => ClientFrame::try_from_slice(bytes),
+        }
+        .map(upgrade)
+        .map_err(|_| DecodeError::InvalidFrame)
+    }
+
+    /// Returns `None` for frames that the protocol version spoken on the connection has no
+    /// equivalent of.
+    pub fn encode_server_frame(&self, frame: ServerFrame) -> Result<Option<Vec<u8>>, EncodeError> {
+        let Some(frame) = downgrade(frame, self.version()) else {
+            return Ok(None);
+        };
+        let bytes = match self.version() {
+            // version 1 clients can only send single byte ids, so this doesn't truncate anything
+            1 => frame.clone().map_id(|id| id as u8).try_to_vec(),
+            _ => frame.try_to_vec(),
+        }
+        .map_err(|_| EncodeError)?;
+        self.switch_after(&frame);
+
+        Ok(Some(bytes))
+    }
+
+    /// Like [`Codec::decode_client_frame`], for JSON. Request ids are never narrowed, since JSON
+    /// doesn't care how wide they are.
+    pub fn decode_client_frame_json(&self, text: &str) -> Result<ClientFrame, DecodeError> {
+        self.settle_format(Format::Json)?;
+        serde_json::from_str(text)
+            .map(upgrade)
+            .map_err(|_| DecodeError::InvalidFrame)
+    }
+
+    pub fn encode_server_frame_json(
+        &self,
+        frame: ServerFrame,
+    ) -> Result<Option<String>, EncodeError> {
+        let Some(frame) = downgrade(frame, self.version()) else {
+            return Ok(None);
+        };
+        let text = serde_json::to_string(&frame).map_err(|_| EncodeError)?;
+        self.switch_after(&frame);
+
+        Ok(Some(text))
+    }
+
+    /// The client side of [`Codec::decode_client_frame`].
+    pub fn encode_client_frame(&self, frame: ClientFrame) -> Result<Vec<u8>, EncodeError> {
+        match self.version() {
+            1 => {
+                let id = u8::try_from(frame.id).map_err(|_| EncodeError)?;
+                frame.map_id(|_| id).try_to_vec()
+            }
+            _ => frame.try_to_vec(),
+        }
+        .map_err(|_| EncodeError)
+    }
+
+    /// The client side of [`Codec::decode_client_frame_json`].
+    pub fn encode_client_frame_json(&self, frame: ClientFrame) -> Result<String, EncodeError> {
+        serde_json::to_string(&frame).map_err(|_| EncodeError)
+    }
+
+    /// The client side of [`Codec::encode_server_frame_json`].
+    pub fn decode_server_frame_json(&self, text: &str) -> Result<ServerFrame, DecodeError> {
+        let frame = serde_json::from_str(text).map_err(|_| DecodeError::InvalidFrame)?;
+        self.switch_after(&frame);
+
+        Ok(frame)
+    }
+
+    /// The client side of [`Codec::encode_server_frame`].
+    pub fn decode_server_frame(&self, bytes: &[u8]) -> Result<ServerFrame, DecodeError> {
+        let frame = match self.version() {
+            1 => ServerFrame::<u8>::try_from_slice(bytes).map(|f| f.map_id(Into::into)),
+            _ => ServerFrame::try_from_slice(bytes),
+        }
+        .map_err(|_| DecodeError::InvalidFrame)?;
+        self.switch_after(&frame);
+
+        Ok(frame)
+    }
+}
+
+/// Replaces frames that were sent by clients speaking older protocol versions with the frames
+/// that replaced them.
+fn upgrade(frame: ClientFrame) -> ClientFrame {
+    match frame.data {
+        ClientFrameType::MsgV1(msg) => ClientFrame {
+            id: frame.id,
+            data: ClientFrameType::Msg {
+                room: DEFAULT_ROOM.to_string(),
+                msg,
+            },
+        },
+        _ => frame,
+    }
+}
+
+/// Replaces frames that didn't exist in older protocol versions with their older equivalents.
+/// Frames that have none are left out for clients speaking version 1, which (unlike later
+/// versions) can't skip frames they don't recognize.
+fn downgrade(frame: ServerFrame, version: u16) -> Option<ServerFrame> {
+    let frame = match frame {
+        // version 1 predates rooms, so there's no telling other rooms apart from the default one
+        ServerFrame::Broadcast { room, .. }
+        | ServerFrame::Present { room, .. }
+        | ServerFrame::Login { room, .. }
+        | ServerFrame::Logout { room, .. }
+            if version < 2 && room != DEFAULT_ROOM =>
+        {
+            return None
+        }
+        ServerFrame::Broadcast { sender, msg, .. } if version < 2 => {
+            ServerFrame::BroadcastV1 { sender, msg }
+        }
+        ServerFrame::Present { handle, .. } if version < 2 => ServerFrame::PresentV1(handle),
+        ServerFrame::Login { handle, .. } if version < 2 => ServerFrame::LoginV1(handle),
+        ServerFrame::Logout { handle, .. } if version < 2 => ServerFrame::LogoutV1(handle),
+        ServerFrame::Receipt { id, .. } if version < 2 => ServerFrame::Okay(id),
+        ServerFrame::Error { id, msg, .. } if version < 3 => ServerFrame::Err(id, msg),
+        ServerFrame::Error { id, code, msg } if code.since() > version => ServerFrame::Error {
+            id,
+            code: ErrorCode::Internal,
+            msg,
+        },
+        ServerFrame::ProtocolError { code, msg } if version >= 3 && code.since() > version => {
+            ServerFrame::ProtocolError {
+                code: ErrorCode::Internal,
+                msg,
+            }
+        }
+        // the connection is closed right after either way
+        ServerFrame::Close { reason, msg } if version >= 3 && reason.since() > version => {
+            ServerFrame::ProtocolError {
+                code: ErrorCode::Internal,
+                msg,
+            }
+        }
+        ServerFrame::Broadcast {
+            msg_id,
+            timestamp,
+            room,
+            sender,
+            msg,
+            ..
+        } if version < 4 => ServerFrame::BroadcastV2 {
+            msg_id,
+            timestamp,
+            room,
+            sender,
+            msg,
+        },
+        ServerFrame::Broadcast {
+            msg_id,
+            timestamp,
+            room,
+            sender,
+            msg,
+            reply_to,
+            ..
+        } if version < 5 => ServerFrame::BroadcastV4 {
+            msg_id,
+            timestamp,
+            room,
+            sender,
+            msg,
+            reply_to,
+        },
+        ServerFrame::Logout { room, handle, .. } if version < 6 => {
+            ServerFrame::LogoutV2 { room, handle }
+        }
+        ServerFrame::HistoryPage { id, room, msgs } if version < 8 => ServerFrame::HistoryPageV2 {
+            id,
+            room,
+            msgs: msgs.into_iter().map(Into::into).collect(),
+        },
+        ServerFrame::ThreadPage {
+            id,
+            room,
+            msg_id,
+            msgs,
+        } if version < 8 => ServerFrame::ThreadPageV4 {
+            id,
+            room,
+            msg_id,
+            msgs: msgs.into_iter().map(Into::into).collect(),
+        },
+        // older clients don't know these, and an error would have to answer a request they might
+        // have sent - the connection is closed right after anyway
+        ServerFrame::ProtocolError { .. }
+        | ServerFrame::Close { .. }
+        | ServerFrame::ServerShutdown { .. }
+            if version < 3 =>
+        {
+            return None
+        }
+        frame if version < 2 && !in_v1(&frame) => return None,
+        frame => frame,
+    };
+    Some(frame)
+}
+
+/// Whether the frame was part of protocol version 1, or is a `Welcome` to a client that asked
+/// for it.
+fn in_v1(frame: &ServerFrame) -> bool {
+    matches!(
+        frame,
+        ServerFrame::Okay(_)
+            | ServerFrame::Err(..)
+            | ServerFrame::BroadcastV1 { .. }
+            | ServerFrame::PresentV1(_)
+            | ServerFrame::LoginV1(_)
+            | ServerFrame::LogoutV1(_)
+            | ServerFrame::Welcome { .. }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::frame::{
+        CloseReason, HistoryMsg, HistoryMsgV2, Limits, LogoutReason, PROTOCOL_VERSION,
+    };
+
+    use super::*;
+
+    fn welcome(protocol_version: u16) -> ServerFrame {
+        ServerFrame::Welcome {
+            id: 0,
+            protocol_version,
+            features: Vec::new(),
+            limits: Limits {
+                max_msg_len: 1,
+                history_page_limit: 1,
+            },
+        }
+    }
+
+    #[test]
+    fn switches_after_welcome() {
+        let codec = Codec::new();
+        assert_eq!(
+            codec.encode_server_frame(ServerFrame::Okay(3)).unwrap(),
+            Some(vec![0, 3])
+        );
+
+        let welcome_bytes = codec
+            .encode_server_frame(welcome(PROTOCOL_VERSION))
+            .unwrap()
+            .unwrap();
+        assert_eq!(welcome_bytes[..2], [9, 0]);
+
+        assert_eq!(codec.version(), PROTOCOL_VERSION);
+        assert_eq!(
+            codec.encode_server_frame(ServerFrame::Okay(3)).unwrap(),
+            Some(vec![0, 3, 0, 0, 0])
+        );
+    }
+
+    #[test]
+    fn client_side_switches_too() {
+        let server = Codec::new();
+        let client = Codec::new();
+
+        let bytes = server
+            .encode_server_frame(welcome(PROTOCOL_VERSION))
+            .unwrap()
+            .unwrap();
+        client.decode_server_frame(&bytes).unwrap();
+
+        let frame = ClientFrame {
+            id: 300,
+            data: ClientFrameType::Logout,
+        };
+        let bytes = client.encode_client_frame(frame).unwrap();
+        assert_eq!(server.decode_client_frame(&bytes).unwrap().id, 300);
+    }
+
+    #[test]
+    fn errors_are_downgraded() {
+        let codec = Codec::new();
+        let error = ServerFrame::error(3, ErrorCode::HandleTaken, "a");
+        assert_eq!(
+            codec.encode_server_frame(error).unwrap(),
+            Some(vec![1, 3, 1, 0, 0, 0, 97])
+        );
+    }
+
+    /// Encodes the frame for a client speaking `version` and decodes it again.
+    fn downgraded(frame: ServerFrame, version: u16) -> Option<ServerFrame> {
+        let server = Codec::new();
+        let client = Codec::new();
+        let bytes = server
+            .encode_server_frame(welcome(version))
+            .unwrap()
+            .unwrap();
+        client.decode_server_frame(&bytes).unwrap();
+
+        let bytes = server.encode_server_frame(frame).unwrap()?;
+        Some(client.decode_server_frame(&bytes).unwrap())
+    }
+
+    #[test]
+    fn newer_error_codes_are_downgraded() {
+        let error = ServerFrame::error(3, ErrorCode::UnknownMsg, "a");
+        assert_eq!(
+            downgraded(error.clone(), 6),
+            Some(ServerFrame::error(3, ErrorCode::Internal, "a"))
+        );
+        assert_eq!(downgraded(error.clone(), 7), Some(error));
+
+        let error = ServerFrame::ProtocolError {
+            code: ErrorCode::InvalidFrame,
+            msg: "a".to_string(),
+        };
+        assert_eq!(
+            downgraded(error, 6),
+            Some(ServerFrame::ProtocolError {
+                code: ErrorCode::Internal,
+                msg: "a".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn newer_close_reasons_are_downgraded() {
+        let close = ServerFrame::Close {
+            reason: CloseReason::IdleTimeout,
+            msg: "a".to_string(),
+        };
+        assert_eq!(
+            downgraded(close.clone(), 5),
+            Some(ServerFrame::ProtocolError {
+                code: ErrorCode::Internal,
+                msg: "a".to_string(),
+            })
+        );
+        assert_eq!(downgraded(close.clone(), 6), Some(close));
+
+        let close = ServerFrame::Close {
+            reason: CloseReason::LoginTimeout,
+            msg: "a".to_string(),
+        };
+        assert_eq!(
+            downgraded(close.clone(), 8),
+            Some(ServerFrame::ProtocolError {
+                code: ErrorCode::Internal,
+                msg: "a".to_string(),
+            })
+        );
+        assert_eq!(downgraded(close.clone(), 9), Some(close));
+    }
+
+    #[test]
+    fn closing_frames_are_dropped_before_version_3() {
+        let frames = [
+            ServerFrame::ProtocolError {
+                code: ErrorCode::InvalidFrame,
+                msg: "a".to_string(),
+            },
+            ServerFrame::Close {
+                reason: CloseReason::TooManyErrors,
+                msg: "a".to_string(),
+            },
+            ServerFrame::ServerShutdown {
+                reason: "a".to_string(),
+                reconnect_after: None,
+            },
+        ];
+        for frame in frames {
+            assert_eq!(downgraded(frame.clone(), 1), None);
+            assert_eq!(downgraded(frame.clone(), 2), None);
+            assert!(downgraded(frame, 3).is_some());
+        }
+    }
+
+    #[test]
+    fn broadcasts_are_downgraded() {
+        let broadcast = ServerFrame::Broadcast {
+            msg_id: 1,
+            timestamp: 2,
+            room: DEFAULT_ROOM.to_string(),
+            sender: "a".to_string(),
+            msg: "b".to_string(),
+            reply_to: Some(0),
+            mentions: vec!["c".to_string()],
+        };
+        assert_eq!(
+            downgraded(broadcast.clone(), 1),
+            Some(ServerFrame::BroadcastV1 {
+                sender: "a".to_string(),
+                msg: "b".to_string(),
+            })
+        );
+        assert_eq!(
+            downgraded(broadcast.clone(), 3),
+            Some(ServerFrame::BroadcastV2 {
+                msg_id: 1,
+                timestamp: 2,
+                room: DEFAULT_ROOM.to_string(),
+                sender: "a".to_string(),
+                msg: "b".to_string(),
+            })
+        );
+        assert_eq!(
+            downgraded(broadcast, 4),
+            Some(ServerFrame::BroadcastV4 {
+                msg_id: 1,
+                timestamp: 2,
+                room: DEFAULT_ROOM.to_string(),
+                sender: "a".to_string(),
+                msg: "b".to_string(),
+                reply_to: Some(0),
+            })
+        );
+    }
+
+    #[test]
+    fn presence_is_downgraded() {
+        let present = ServerFrame::Present {
+            room: DEFAULT_ROOM.to_string(),
+            handle: "a".to_string(),
+        };
+        assert_eq!(
+            downgraded(present, 1),
+            Some(ServerFrame::PresentV1("a".to_string()))
+        );
+
+        let login = ServerFrame::Login {
+            room: DEFAULT_ROOM.to_string(),
+            handle: "a".to_string(),
+        };
+        assert_eq!(
+            downgraded(login, 1),
+            Some(ServerFrame::LoginV1("a".to_string()))
+        );
+    }
+
+    #[test]
+    fn logouts_are_downgraded() {
+        let logout = ServerFrame::Logout {
+            room: DEFAULT_ROOM.to_string(),
+            handle: "a".to_string(),
+            reason: LogoutReason::Timeout,
+        };
+        assert_eq!(
+            downgraded(logout.clone(), 1),
+            Some(ServerFrame::LogoutV1("a".to_string()))
+        );
+        assert_eq!(
+            downgraded(logout, 5),
+            Some(ServerFrame::LogoutV2 {
+                room: DEFAULT_ROOM.to_string(),
+                handle: "a".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn receipts_are_downgraded() {
+        let receipt = ServerFrame::Receipt {
+            id: 3,
+            msg_id: 1,
+            timestamp: 2,
+        };
+        assert_eq!(downgraded(receipt, 1), Some(ServerFrame::Okay(3)));
+    }
+
+    #[test]
+    fn history_is_downgraded() {
+        let page = ServerFrame::HistoryPage {
+            id: 3,
+            room: "r".to_string(),
+            msgs: vec![HistoryMsg {
+                msg_id: 1,
+                timestamp: 2,
+                sender: "a".to_string(),
+                msg: "b".to_string(),
+                reply_to: Some(0),
+            }],
+        };
+        assert_eq!(
+            downgraded(page, 7),
+            Some(ServerFrame::HistoryPageV2 {
+                id: 3,
+                room: "r".to_string(),
+                msgs: vec![HistoryMsgV2 {
+                    msg_id: 1,
+                    timestamp: 2,
+                    sender: "a".to_string(),
+                    msg: "b".to_string(),
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn v1_clients_only_get_v1_frames() {
+        let typing = ServerFrame::Typing {
+            room: "r".to_string(),
+            handle: "a".to_string(),
+            typing: true,
+        };
+        assert_eq!(downgraded(typing.clone(), 1), None);
+        assert_eq!(downgraded(typing.clone(), 2), Some(typing));
+    }
+
+    #[test]
+    fn v1_clients_only_hear_from_the_default_room() {
+        let broadcast = ServerFrame::Broadcast {
+            msg_id: 1,
+            timestamp: 2,
+            room: "r".to_string(),
+            sender: "a".to_string(),
+            msg: "b".to_string(),
+            reply_to: None,
+            mentions: Vec::new(),
+        };
+        assert_eq!(downgraded(broadcast, 1), None);
+
+        let present = ServerFrame::Present {
+            room: "r".to_string(),
+            handle: "a".to_string(),
+        };
+        assert_eq!(downgraded(present.clone(), 1), None);
+        assert_eq!(downgraded(present.clone(), 2), Some(present));
+    }
+
+    #[test]
+    fn v1_msgs_go_to_the_default_room() {
+        let codec = Codec::new();
+        assert_eq!(
+            codec
+                .decode_client_frame(&[22, 1, 2, 0, 0, 0, 104, 105])
+                .unwrap(),
+            ClientFrame {
+                id: 22,
+                data: ClientFrameType::Msg {
+                    room: DEFAULT_ROOM.to_string(),
+                    msg: "hi".to_string(),
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn json() {
+        let server = Codec::new();
+        let client = Codec::new();
+
+        let hello = ClientFrame {
+            id: 300,
+            data: ClientFrameType::Hello {
+                protocol_version: PROTOCOL_VERSION,
+                capabilities: Vec::new(),
+            },
+        };
+        let text = client.encode_client_frame_json(hello).unwrap();
+        assert_eq!(server.decode_client_frame_json(&text).unwrap().id, 300);
+        assert_eq!(server.format(), Some(Format::Json));
+
+        let text = server
+            .encode_server_frame_json(welcome(PROTOCOL_VERSION))
+            .unwrap()
+            .unwrap();
+        client.decode_server_frame_json(&text).unwrap();
+        assert_eq!(server.version(), PROTOCOL_VERSION);
+        assert_eq!(client.version(), PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn json_skips_version_1() {
+        let server = Codec::new();
+        let text = r#"{"id":0,"data":{"login":"bob"}}"#;
+        server.decode_client_frame_json(text).unwrap();
+        assert_eq!(server.version(), PROTOCOL_VERSION);
+
+        let client = Codec::new();
+        let hello = ClientFrame {
+            id: 0,
+            data: ClientFrameType::Hello {
+                protocol_version: 2,
+                capabilities: Vec::new(),
+            },
+        };
+        let text = client.encode_client_frame_json(hello).unwrap();
+        let server = Codec::new();
+        server.decode_client_frame_json(&text).unwrap();
+        server.encode_server_frame_json(welcome(2)).unwrap();
+        assert_eq!(server.version(), 2);
+    }
+
+    #[test]
+    fn formats_dont_mix() {
+        let codec = Codec::new();
+        codec.decode_client_frame(&[0, 2]).unwrap();
+        assert!(matches!(
+            codec.decode_client_frame_json(r#"{"id":0,"data":"logout"}"#),
+            Err(DecodeError::MixedFormats)
+        ));
+    }
+
+    #[test]
+    fn wide_ids_dont_fit_in_v1() {
+        let client = Codec::new();
+        let frame = ClientFrame {
+            id: 300,
+            data: ClientFrameType::Logout,
+        };
+        assert!(client.encode_client_frame(frame).is_err());
+    }
+}
@@ -0,0 +1,69 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// The longest handle (in characters) users can log in with.
+    pub max_handle_len: usize,
+    /// The longest room name (in characters) users can join.
+    pub max_room_len: usize,
+    /// The longest message (in bytes) users can send.
+    pub max_msg_len: usize,
+    /// The longest reaction (in bytes) users can react with. Some emoji take up quite a few.
+    pub max_reaction_len: usize,
+    /// The longest status text (in bytes) users can set.
+    pub max_status_len: usize,
+    /// How long a user can be inactive before they're shown as away.
+    pub away_after: Duration,
+    /// How long a client gets to complete the transport handshake (e.g. the WebSocket upgrade).
+    pub handshake_timeout: Duration,
+    /// How long a client gets to log in, counted from when it connected or logged out.
+    pub login_timeout: Duration,
+    /// How long a connection can stay quiet before it's closed and the user logged out. Answering
+    /// the server's WebSocket pings counts, see [`crate::protocol::ws::PING_INTERVAL`].
+    pub idle_timeout: Duration,
+    /// How long connections get to wind down when the server shuts down.
+    pub drain_timeout: Duration,
+    /// How many invalid or unexpected frames a client can send before it's disconnected.
+    pub max_protocol_errors: usize,
+    /// How many of the most recent messages are kept in memory. With a `history_file`, older ones
+    /// are read back from it when they're asked for.
+    pub history_capacity: usize,
+    /// How long a user is shown as typing after their last typing notification.
+    pub typing_timeout: Duration,
+    /// How many of the most recent messages in a room a user is sent upon entering it.
+    pub history_replay: usize,
+    /// The most messages a client can fetch with a single history request.
+    pub history_page_limit: usize,
+    /// If set, message history is persisted to this file as an append-only log.
+    pub history_file: Option<PathBuf>,
+    /// The PEM certificate (chain) TLS listeners present to clients.
+    pub tls_cert_file: Option<PathBuf>,
+    /// The PEM private key for `tls_cert_file`.
+    pub tls_key_file: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_handle_len: 32,
+            max_room_len: 32,
+            max_msg_len: 4096,
+            max_reaction_len: 32,
+            max_status_len: 128,
+            away_after: Duration::from_secs(5 * 60),
+            handshake_timeout: Duration::from_secs(10),
+            login_timeout: Duration::from_secs(30),
+            idle_timeout: Duration::from_secs(60),
+            drain_timeout: Duration::from_secs(5),
+            max_protocol_errors: 10,
+            typing_timeout: Duration::from_secs(5),
+            history_capacity: 1000,
+            history_replay: 50,
+            history_page_limit: 100,
+            history_file: None,
+            tls_cert_file: None,
+            tls_key_file: None,
+        }
+    }
+}
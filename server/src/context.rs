@@ -1,30 +1,155 @@
+//! State shared by all connections. Whatever it hands out (members, markers, statuses) is a
+//! snapshot that doesn't hold any locks, so it's safe to keep across await points.
+
+mod read_markers;
+mod rooms;
+
+use std::collections::HashSet;
+use std::io::Error as IoError;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
-use dashmap::{mapref::multiple::RefMulti, DashMap};
+use dashmap::{mapref::entry::Entry, DashMap};
 use futures_channel::mpsc::{self, TrySendError, UnboundedReceiver, UnboundedSender};
+use tokio::sync::watch;
 
-use crate::frame::ServerFrame;
+use crate::config::Config;
+use crate::frame::{MsgId, Presence, ServerFrame};
+use crate::store::{FileStore, MemoryStore, MessageStore};
+use crate::Shutdown;
 
-#[derive(Default, Debug, Clone)]
+pub use read_markers::ReadMarkers;
+pub use rooms::Rooms;
+
+#[derive(Debug, Clone)]
 pub struct Context {
+    config: Arc<Config>,
     users: UserPool,
+    rooms: Rooms,
+    read_markers: ReadMarkers,
+    store: Arc<dyn MessageStore>,
+    next_msg_id: Arc<AtomicU64>,
+    shutdown: Arc<watch::Sender<Option<Shutdown>>>,
 }
 
 impl Context {
+    #[cfg(test)]
     pub fn new() -> Self {
-        Self::default()
+        Self::with_store(Config::default(), MemoryStore::new(0))
+    }
+
+    pub fn from_config(config: Config) -> Result<Self, IoError> {
+        Ok(match &config.history_file {
+            Some(path) => {
+                let store = FileStore::open(path, config.history_capacity)?;
+                Self::with_store(config, store)
+            }
+            None => {
+                let store = MemoryStore::new(config.history_capacity);
+                Self::with_store(config, store)
+            }
+        })
+    }
+
+    pub fn with_store(config: Config, store: impl MessageStore + 'static) -> Self {
+        let next_msg_id = store.last_id().map(|id| id + 1).unwrap_or(0);
+
+        Self {
+            config: Arc::new(config),
+            users: UserPool::default(),
+            rooms: Rooms::default(),
+            read_markers: ReadMarkers::default(),
+            store: Arc::new(store),
+            next_msg_id: Arc::new(AtomicU64::new(next_msg_id)),
+            shutdown: Arc::new(watch::channel(None).0),
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn store(&self) -> &dyn MessageStore {
+        &*self.store
     }
 
     pub fn users(&self) -> &UserPool {
         &self.users
     }
+
+    pub fn rooms(&self) -> &Rooms {
+        &self.rooms
+    }
+
+    pub fn read_markers(&self) -> &ReadMarkers {
+        &self.read_markers
+    }
+
+    pub fn next_msg_id(&self) -> MsgId {
+        self.next_msg_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Lets every connection know the server is shutting down.
+    pub fn shut_down(&self, shutdown: Shutdown) {
+        self.shutdown.send_replace(Some(shutdown));
+    }
+
+    /// Resolves once the server is shutting down, right away if it already is.
+    pub async fn shutting_down(&self) -> Shutdown {
+        let mut rx = self.shutdown.subscribe();
+        loop {
+            if let Some(shutdown) = &*rx.borrow() {
+                return shutdown.clone();
+            }
+            // the sender lives as long as this context, so this can't fail
+            let _ = rx.changed().await;
+        }
+    }
+
+    pub fn broadcast_to_room(&self, room: &str, frame: ServerFrame) {
+        for member in self.rooms.members(room) {
+            self.users.send(&member, frame.clone());
+        }
+    }
+
+    pub fn broadcast_to_room_except(&self, room: &str, handle: &str, frame: ServerFrame) {
+        for member in self.rooms.members(room).iter().filter(|m| *m != handle) {
+            self.users.send(member, frame.clone());
+        }
+    }
+
+    /// Sends the frame once to everyone sharing a room with the user, except the user.
+    pub fn broadcast_to_peers(&self, handle: &str, frame: ServerFrame) {
+        let mut peers = HashSet::new();
+        for room in self.rooms.rooms_of(handle) {
+            peers.extend(self.rooms.members(&room));
+        }
+        peers.remove(handle);
+
+        for peer in peers {
+            self.users.send(&peer, frame.clone());
+        }
+    }
 }
 
 type Tx = UnboundedSender<ServerFrame>;
 type Rx = UnboundedReceiver<ServerFrame>;
 
+/// What a user lets others know about their availability.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Status {
+    pub presence: Presence,
+    pub text: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+struct User {
+    tx: Tx,
+    status: Status,
+}
+
 #[derive(Default, Debug, Clone)]
-pub struct UserPool(Arc<DashMap<String, Tx>>);
+pub struct UserPool(Arc<DashMap<String, User>>);
 
 impl UserPool {
     pub fn register_user_with_callback<F>(
@@ -40,7 +165,13 @@ impl UserPool {
             return None;
         }
         let (tx, rx) = mpsc::unbounded();
-        self.0.insert(handle.clone(), tx.clone());
+        self.0.insert(
+            handle.clone(),
+            User {
+                tx: tx.clone(),
+                status: Status::default(),
+            },
+        );
         Some(UserGuard {
             handle,
             pool: self,
@@ -50,25 +181,53 @@ impl UserPool {
         })
     }
 
-    /// Don't hold this iterator or the guards it produces across await points!
-    pub fn users(&self) -> Users<'_> {
-        Users {
-            iter: self.0.iter(),
+    /// Returns `false` if there's no such user logged in.
+    pub fn send(&self, handle: &str, frame: ServerFrame) -> bool {
+        match self.0.get(handle) {
+            Some(user) => user.tx.unbounded_send(frame).is_ok(),
+            None => false,
         }
     }
 
-    pub fn broadcast(&self, frame: ServerFrame) {
-        for r in self.0.iter() {
-            let tx = r.value();
-            let _ = tx.unbounded_send(frame.clone());
-        }
+    /// The user's status, or `None` if there's no such user logged in.
+    pub fn status(&self, handle: &str) -> Option<Status> {
+        self.0.get(handle).map(|user| user.status.clone())
+    }
+
+    /// Returns the user's previous status, or `None` if there's no such user logged in.
+    pub fn set_status(&self, handle: &str, status: Status) -> Option<Status> {
+        self.0
+            .get_mut(handle)
+            .map(|mut user| std::mem::replace(&mut user.status, status))
     }
 
-    pub fn broadcast_except(&self, handle: &str, frame: ServerFrame) {
-        for r in self.0.iter().filter(|r| r.key() != handle) {
-            let tx = r.value();
-            let _ = tx.unbounded_send(frame.clone());
+    /// Moves the user to a new handle. Returns `false` if the new handle is taken.
+    ///
+    /// The new handle is claimed before the old one is released, so neither is up for grabs in
+    /// between. In the meantime, the user can be reached under both, and `move_along` gets to
+    /// update whatever else refers to the handle.
+    async fn rename(
+        &self,
+        old: &str,
+        new: String,
+        move_along: impl AsyncFnOnce(&str, &str),
+    ) -> bool {
+        // Careful: both handles might live in the same shard, so only hold one lock at a time.
+        let Some(user) = self.0.get(old).map(|user| user.clone()) else {
+            return false;
+        };
+        match self.0.entry(new.clone()) {
+            Entry::Occupied(_) => return false,
+            Entry::Vacant(entry) => {
+                entry.insert(user);
+            }
+        }
+        move_along(old, &new).await;
+        // the status might have changed under the old handle since it was copied
+        if let Some((_, user)) = self.0.remove(old) {
+            self.set_status(&new, user.status);
         }
+        true
     }
 
     fn remove_user(&self, handle: &str) {
@@ -76,66 +235,6 @@ impl UserPool {
     }
 }
 
-pub struct Users<'a> {
-    iter: dashmap::iter::Iter<'a, String, Tx>,
-}
-
-impl<'a> Iterator for Users<'a> {
-    type Item = UserHandleRef<'a>;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        self.iter.next().map(UserHandleRef)
-    }
-}
-
-pub struct UserHandleRef<'a>(RefMulti<'a, String, Tx>);
-
-impl std::ops::Deref for UserHandleRef<'_> {
-    type Target = str;
-
-    fn deref(&self) -> &Self::Target {
-        self.0.key()
-    }
-}
-
-impl std::cmp::PartialEq for UserHandleRef<'_> {
-    fn eq(&self, other: &Self) -> bool {
-        self.0.key() == other.0.key()
-    }
-}
-
-impl Eq for UserHandleRef<'_> {}
-
-impl std::cmp::PartialEq<&str> for UserHandleRef<'_> {
-    fn eq(&self, other: &&str) -> bool {
-        self.0.key() == other
-    }
-}
-
-impl std::cmp::PartialEq<UserHandleRef<'_>> for &str {
-    fn eq(&self, other: &UserHandleRef) -> bool {
-        self == other.0.key()
-    }
-}
-
-impl std::hash::Hash for UserHandleRef<'_> {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.0.key().hash(state);
-    }
-}
-
-impl std::fmt::Debug for UserHandleRef<'_> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_tuple("UserHandleRef").field(&self.0.key()).finish()
-    }
-}
-
-impl From<UserHandleRef<'_>> for String {
-    fn from(value: UserHandleRef<'_>) -> Self {
-        value.0.key().to_string()
-    }
-}
-
 pub struct UserGuard<'p, F>
 where
     F: Fn(&str, &UserPool),
@@ -151,6 +250,7 @@ impl<F> UserGuard<'_, F>
 where
     F: Fn(&str, &UserPool),
 {
+    #[allow(clippy::result_large_err)]
     pub fn send(&self, frame: ServerFrame) -> Result<(), TrySendError<ServerFrame>> {
         self.tx.unbounded_send(frame)?;
 
@@ -164,6 +264,24 @@ where
     pub fn take_rx(&mut self) -> Option<Rx> {
         self.rx.take()
     }
+
+    /// Returns `false` if the new handle is taken. See [`UserPool::rename`] for `move_along`.
+    pub async fn rename(
+        &mut self,
+        new: impl Into<String>,
+        move_along: impl AsyncFnOnce(&str, &str),
+    ) -> bool {
+        let new = new.into();
+        if !self
+            .pool
+            .rename(&self.handle, new.clone(), move_along)
+            .await
+        {
+            return false;
+        }
+        self.handle = new;
+        true
+    }
 }
 
 impl<F> Drop for UserGuard<'_, F>
@@ -171,16 +289,14 @@ where
     F: Fn(&str, &UserPool),
 {
     fn drop(&mut self) {
-        self.pool.remove_user(&self.handle);
+        // the callback might want to know what the user was up to
         (self.on_drop)(self.handle(), self.pool);
+        self.pool.remove_user(&self.handle);
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::collections::HashSet;
-
-    use dashmap::try_result::TryResult;
     use futures_util::StreamExt as _;
 
     use super::*;
@@ -242,71 +358,72 @@ mod tests {
         assert!(*dropped.read().unwrap());
     }
 
-    #[test]
-    fn iterate_users() {
+    #[tokio::test]
+    async fn rename() {
         let pool = UserPool::new();
+        let mut bob = pool.register_user("bob").unwrap();
         let _anne = pool.register_user("anne").unwrap();
-        let _bob = pool.register_user("bob").unwrap();
-
-        let users: Vec<_> = pool.users().map(String::from).collect();
 
-        assert_eq!(
-            users.iter().map(String::as_str).collect::<HashSet<_>>(),
-            HashSet::<&str>::from(["anne", "bob"])
+        assert!(
+            !bob.rename("anne", async |_, _| panic!("anne is taken"))
+                .await
         );
-    }
-
-    #[test]
-    fn iterate_users_safety() {
-        let pool = UserPool::new();
-        let _anne = pool.register_user("anne").unwrap();
-        let _bob = pool.register_user("bob").unwrap();
-        let _dudeson = pool.register_user("dudeson").unwrap();
-
-        let _users = pool
-            .users()
-            .filter(|u| *u != "dudeson") // this will drop the guard for "dudeson"
-            .collect::<HashSet<_>>(); // we're only holding guards for "anne" and "bob"
+        assert!(
+            bob.rename("robert", async |old, new| {
+                assert!(pool.send(old, ServerFrame::Okay(1)));
+                assert!(pool.send(new, ServerFrame::Okay(2)));
+                let status = Status {
+                    presence: Presence::Busy,
+                    text: None,
+                };
+                pool.set_status(old, status);
+            })
+            .await
+        );
+        assert_eq!(bob.handle(), "robert");
+        assert!(pool.contains("robert"));
+        assert!(!pool.contains("bob"));
+        assert_eq!(pool.status("robert").unwrap().presence, Presence::Busy);
 
-        assert!(matches!(pool.0.try_get_mut("anne"), TryResult::Locked));
-        assert!(matches!(pool.0.try_get_mut("bob"), TryResult::Locked));
-        assert!(matches!(pool.0.try_get_mut("claire"), TryResult::Absent));
-        assert!(matches!(
-            pool.0.try_get_mut("dudeson"),
-            TryResult::Present(_)
-        ));
+        drop(bob);
+        assert!(!pool.contains("robert"));
     }
 
     #[tokio::test]
-    async fn broadcast() {
+    async fn send_to_handle() {
         let pool = UserPool::new();
         let mut anne = pool.register_user("anne").unwrap();
-        let mut bob = pool.register_user("bob").unwrap();
 
-        let frame = ServerFrame::Broadcast {
-            sender: "system".to_string(),
-            msg: "hi".to_string(),
-        };
-        pool.broadcast(frame.clone());
+        let frame = ServerFrame::Okay(42);
+        assert!(pool.send("anne", frame.clone()));
+        assert!(!pool.send("bob", frame.clone()));
 
         assert_eq!(frame, anne.take_rx().unwrap().next().await.unwrap());
-        assert_eq!(frame, bob.take_rx().unwrap().next().await.unwrap());
     }
 
     #[tokio::test]
-    async fn broadcast_except() {
-        let pool = UserPool::new();
-        let mut anne = pool.register_user("anne").unwrap();
-        let mut bob = pool.register_user("bob").unwrap();
+    async fn room_broadcast() {
+        let cx = Context::new();
+        let mut anne = cx.users().register_user("anne").unwrap();
+        let mut bob = cx.users().register_user("bob").unwrap();
+        let mut claire = cx.users().register_user("claire").unwrap();
+        cx.rooms().join("rust", "anne");
+        cx.rooms().join("rust", "bob");
 
         let frame = ServerFrame::Broadcast {
-            sender: "system".to_string(),
+            msg_id: 0,
+            timestamp: 0,
+            room: "rust".to_string(),
+            sender: "anne".to_string(),
             msg: "hi".to_string(),
+            reply_to: None,
+            mentions: Vec::new(),
         };
-        pool.broadcast_except("bob", frame.clone());
+        cx.broadcast_to_room_except("rust", "anne", frame.clone());
 
-        assert_eq!(frame, anne.take_rx().unwrap().next().await.unwrap());
-        assert!(bob.rx.as_mut().unwrap().try_next().is_err());
+        assert_eq!(frame, bob.take_rx().unwrap().next().await.unwrap());
+        assert!(anne.rx.as_mut().unwrap().try_next().is_err());
+        assert!(claire.rx.as_mut().unwrap().try_next().is_err());
     }
 
     #[tokio::test]
@@ -325,7 +442,10 @@ mod tests {
         let pool = UserPool::new();
         let mut anne = pool.register_user("anne").unwrap();
 
-        let login_frame = ServerFrame::Login("bob".to_string());
+        let login_frame = ServerFrame::Login {
+            room: "lobby".to_string(),
+            handle: "bob".to_string(),
+        };
         let okay_frame = ServerFrame::Okay(42);
 
         anne.send(login_frame.clone()).unwrap();
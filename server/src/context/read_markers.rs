@@ -0,0 +1,100 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use dashmap::DashMap;
+
+use crate::frame::MsgId;
+
+/// The id of the last message each user has read, per room.
+///
+/// Markers are kept after users leave a room, so everyone can still see who read what. They're
+/// dropped when the user logs out (so that whoever takes the handle next starts afresh) and when
+/// the room stops existing.
+#[derive(Default, Debug, Clone)]
+pub struct ReadMarkers(Arc<DashMap<String, HashMap<String, MsgId>>>);
+
+impl ReadMarkers {
+    /// Moves the user's marker forward. Returns `false` if it already was at or past `msg_id`.
+    pub fn mark(&self, room: impl Into<String>, handle: impl Into<String>, msg_id: MsgId) -> bool {
+        let mut markers = self.0.entry(room.into()).or_default();
+        match markers.entry(handle.into()) {
+            Entry::Occupied(mut marker) if *marker.get() < msg_id => {
+                marker.insert(msg_id);
+                true
+            }
+            Entry::Occupied(_) => false,
+            Entry::Vacant(marker) => {
+                marker.insert(msg_id);
+                true
+            }
+        }
+    }
+
+    /// Moves the user's markers over to their new handle.
+    pub fn rename(&self, old: &str, new: &str) {
+        for mut markers in self.0.iter_mut() {
+            if let Some(msg_id) = markers.remove(old) {
+                markers.insert(new.to_string(), msg_id);
+            }
+        }
+    }
+
+    /// Drops the user's markers in every room.
+    pub fn forget_user(&self, handle: &str) {
+        self.0.retain(|_, markers| {
+            markers.remove(handle);
+            !markers.is_empty()
+        });
+    }
+
+    pub fn forget_room(&self, room: &str) {
+        self.0.remove(room);
+    }
+
+    /// Each user's marker in the room, in no particular order.
+    pub fn markers(&self, room: &str) -> Vec<(String, MsgId)> {
+        self.0
+            .get(room)
+            .map(|markers| {
+                markers
+                    .iter()
+                    .map(|(handle, msg_id)| (handle.clone(), *msg_id))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn markers_only_move_forward() {
+        let markers = ReadMarkers::default();
+        assert!(markers.mark("rust", "anne", 3));
+        assert!(!markers.mark("rust", "anne", 3));
+        assert!(!markers.mark("rust", "anne", 2));
+        assert!(markers.mark("rust", "anne", 5));
+        assert!(markers.mark("flutter", "anne", 1));
+
+        assert_eq!(markers.markers("rust"), [("anne".to_string(), 5)]);
+        assert!(markers.markers("lobby").is_empty());
+    }
+
+    #[test]
+    fn forget() {
+        let markers = ReadMarkers::default();
+        markers.mark("rust", "anne", 3);
+        markers.mark("rust", "bob", 2);
+        markers.mark("flutter", "anne", 1);
+
+        markers.forget_user("anne");
+        assert_eq!(markers.markers("rust"), [("bob".to_string(), 2)]);
+        assert!(!markers.0.contains_key("flutter"));
+
+        markers.forget_room("rust");
+        assert!(markers.0.is_empty());
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use dashmap::DashMap;
+
+/// A registry of rooms and the handles of users who joined them.
+///
+/// A room exists for as long as it has at least one member.
+#[derive(Default, Debug, Clone)]
+pub struct Rooms(Arc<DashMap<String, HashSet<String>>>);
+
+impl Rooms {
+    /// Returns `false` if the user was already a member of the room.
+    pub fn join(&self, room: impl Into<String>, handle: impl Into<String>) -> bool {
+        self.0.entry(room.into()).or_default().insert(handle.into())
+    }
+
+    /// Returns `false` if the user wasn't a member of the room.
+    pub fn leave(&self, room: &str, handle: &str) -> bool {
+        let left = match self.0.get_mut(room) {
+            Some(mut members) => members.remove(handle),
+            None => false,
+        };
+        self.0.remove_if(room, |_, members| members.is_empty());
+        left
+    }
+
+    /// Removes the user from every room they're in and returns the names of those rooms.
+    pub fn leave_all(&self, handle: &str) -> Vec<String> {
+        let mut left = Vec::new();
+        self.0.retain(|room, members| {
+            if members.remove(handle) {
+                left.push(room.clone());
+            }
+            !members.is_empty()
+        });
+        left
+    }
+
+    /// Replaces the user's handle in every room they're in.
+    pub fn rename(&self, old: &str, new: &str) {
+        for mut members in self.0.iter_mut() {
+            if members.remove(old) {
+                members.insert(new.to_string());
+            }
+        }
+    }
+
+    pub fn exists(&self, room: &str) -> bool {
+        self.0.contains_key(room)
+    }
+
+    pub fn is_member(&self, room: &str, handle: &str) -> bool {
+        self.0
+            .get(room)
+            .map(|members| members.contains(handle))
+            .unwrap_or(false)
+    }
+
+    /// The rooms the user is a member of.
+    pub fn rooms_of(&self, handle: &str) -> Vec<String> {
+        self.0
+            .iter()
+            .filter(|r| r.value().contains(handle))
+            .map(|r| r.key().clone())
+            .collect()
+    }
+
+    /// The handles of the room's members, in no particular order. Empty if the room doesn't
+    /// exist.
+    pub fn members(&self, room: &str) -> Vec<String> {
+        self.0
+            .get(room)
+            .map(|members| members.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn join_and_leave() {
+        let rooms = Rooms::default();
+        assert!(rooms.join("rust", "anne"));
+        assert!(!rooms.join("rust", "anne"));
+        assert!(rooms.is_member("rust", "anne"));
+
+        assert!(rooms.leave("rust", "anne"));
+        assert!(!rooms.leave("rust", "anne"));
+        assert!(!rooms.is_member("rust", "anne"));
+    }
+
+    #[test]
+    fn empty_rooms_are_removed() {
+        let rooms = Rooms::default();
+        rooms.join("rust", "anne");
+        rooms.leave("rust", "anne");
+        assert!(!rooms.0.contains_key("rust"));
+    }
+
+    #[test]
+    fn leave_all() {
+        let rooms = Rooms::default();
+        rooms.join("rust", "anne");
+        rooms.join("flutter", "anne");
+        rooms.join("flutter", "bob");
+
+        let mut left = rooms.leave_all("anne");
+        left.sort();
+
+        assert_eq!(left, ["flutter", "rust"]);
+        assert_eq!(rooms.members("flutter"), ["bob"]);
+        assert_eq!(rooms.rooms_of("bob"), ["flutter"]);
+        assert!(rooms.members("rust").is_empty());
+    }
+
+    #[test]
+    fn rename() {
+        let rooms = Rooms::default();
+        rooms.join("rust", "anne");
+        rooms.join("rust", "bob");
+        rooms.rename("anne", "annie");
+
+        let mut members = rooms.members("rust");
+        members.sort();
+        assert_eq!(members, ["annie", "bob"]);
+    }
+}
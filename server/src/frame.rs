@@ -1,37 +1,765 @@
 use borsh::{BorshDeserialize, BorshSerialize};
+use serde::{Deserialize, Serialize};
+
+/// The newest protocol version this server speaks.
+///
+/// Version 1 is the original protocol: a single room, and single byte request ids.
+/// Version 2 added rooms, direct messages, history and message ids, and widened request ids to
+/// [`RequestId`].
+/// Version 3 introduced [`ServerFrame::Error`] with an [`ErrorCode`].
+/// Version 4 added `reply_to` to [`ServerFrame::Broadcast`].
+/// Version 5 added `mentions` to [`ServerFrame::Broadcast`].
+/// Version 6 added `reason` to [`ServerFrame::Logout`] and [`CloseReason::IdleTimeout`].
+/// Version 7 added the error codes from [`ErrorCode::InvalidFrame`] on.
+/// Version 8 added `reply_to` to [`HistoryMsg`].
+/// Version 9 added [`CloseReason::LoginTimeout`].
+pub const PROTOCOL_VERSION: u16 = 9;
+
+/// The oldest protocol version this server still speaks. Clients that log in without saying
+/// [`ClientFrameType::Hello`] first are assumed to speak this version, and get the frames they
+/// know in the layout they know (see `codec::downgrade`). JSON came later, so clients that send
+/// JSON are assumed to speak [`PROTOCOL_VERSION`] instead.
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// Optional protocol features this server supports, advertised in [`ServerFrame::Welcome`].
+pub const FEATURES: &[&str] = &[
+    "rooms",
+    "direct_msgs",
+    "history",
+    "edits",
+    "reactions",
+    "typing",
+    "read_markers",
+    "presence",
+    "threads",
+    "mentions",
+];
+
+/// The room every user is placed in upon logging in.
+pub const DEFAULT_ROOM: &str = "lobby";
+
+/// Identifies a client's request so that the server's response can refer to it. In protocol
+/// version 1, this was a `u8`.
+pub type RequestId = u32;
+
+/// A server-assigned message id. These increase monotonically.
+pub type MsgId = u64;
+
+/// Milliseconds since the Unix epoch (UTC).
+pub type Timestamp = u64;
 
 // Remember: the order of named fields in a struct intended for borsh (de)serialization matters!
 // Changing this order breaks the protocol. Plan accordingly.
+//
+// New frame types can be added without bumping the protocol version - clients are expected to
+// skip frames they don't recognize. Changing the layout of an existing frame requires a new
+// version and a way to downgrade the frame for older clients (see `codec::downgrade`).
+//
+// Frames can also be sent as JSON (see `Codec`), with variant names in snake_case. Renaming a
+// variant or field changes the JSON representation, so that's a breaking change too.
 
-#[derive(Debug, PartialEq, Eq, Hash, BorshSerialize, BorshDeserialize)]
-pub struct ClientFrame {
-    pub id: u8,
+#[derive(Debug, PartialEq, Eq, Hash, BorshSerialize, BorshDeserialize, Serialize, Deserialize)]
+pub struct ClientFrame<Id = RequestId> {
+    pub id: Id,
     pub data: ClientFrameType,
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, BorshSerialize, BorshDeserialize)]
+impl<Id> ClientFrame<Id> {
+    pub fn map_id<T>(self, f: impl FnOnce(Id) -> T) -> ClientFrame<T> {
+        ClientFrame {
+            id: f(self.id),
+            data: self.data,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, BorshSerialize, BorshDeserialize, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 #[repr(u8)]
 pub enum ClientFrameType {
     Login(String) = 0,
-    Msg(String) = 1,
+    /// What clients speaking protocol version 1 send instead of [`ClientFrameType::Msg`]. Posts
+    /// to [`DEFAULT_ROOM`].
+    MsgV1(String) = 1,
     Logout = 2,
+    Join(String) = 3,
+    Leave(String) = 4,
+    DirectMsg {
+        to: String,
+        msg: String,
+    } = 5,
+    /// Requests messages older than `before` (or the most recent ones if `before` is `None`).
+    /// The server might return fewer than `limit` messages.
+    History {
+        room: String,
+        before: Option<MsgId>,
+        limit: u32,
+    } = 6,
+    /// Optional, but if sent, it has to be the first frame on a connection.
+    Hello {
+        protocol_version: u16,
+        capabilities: Vec<String>,
+    } = 7,
+    /// Replaces the text of one of the user's own messages. Mentions are taken from the new
+    /// text, and users it mentions for the first time get a [`ServerFrame::Mentioned`].
+    Edit {
+        msg_id: MsgId,
+        new_text: String,
+    } = 8,
+    /// Deletes one of the user's own messages.
+    Delete {
+        msg_id: MsgId,
+    } = 9,
+    /// Adds (`on == true`) or removes the user's reaction to a message.
+    React {
+        msg_id: MsgId,
+        emoji: String,
+        on: bool,
+    } = 10,
+    /// Lets the other members of the room know the user is typing. Clients should keep sending
+    /// this every few seconds for as long as the user is typing. There's no response.
+    Typing {
+        room: String,
+    } = 11,
+    /// Marks every message in the room up to and including this one as read.
+    MarkRead(MsgId) = 12,
+    SetStatus {
+        presence: Presence,
+        status: Option<String>,
+    } = 13,
+    /// Changes the user's handle without logging out. Messages sent before keep the old handle.
+    /// Messages belong to the handle they were sent under, so the user can't edit or delete
+    /// those anymore - whoever takes the old handle next can. Reactions, read markers and
+    /// typing indicators move along with the user. Renaming to the current handle is an
+    /// [`ErrorCode::InvalidHandle`].
+    Rename(String) = 14,
+    /// Posts a reply to a message, in the room the message was posted to. Replies to replies
+    /// are attached to the message that started the thread.
+    Reply {
+        reply_to: MsgId,
+        msg: String,
+    } = 15,
+    /// Requests replies to a message, paged like [`ClientFrameType::History`].
+    Thread {
+        msg_id: MsgId,
+        before: Option<MsgId>,
+        limit: u32,
+    } = 16,
+    /// Answered with a [`ServerFrame::Pong`]. Connections that stay quiet for too long are
+    /// closed, so clients should ping when there's nothing else to send. Pinging doesn't count as
+    /// activity, so users still go away while their client pings.
+    Ping = 17,
+    Msg {
+        room: String,
+        msg: String,
+    } = 18,
+}
+
+#[derive(
+    Debug, Clone, PartialEq, Eq, Hash, BorshSerialize, BorshDeserialize, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+#[repr(u8)]
+pub enum ServerFrame<Id = RequestId> {
+    Okay(Id) = 0,
+    /// What clients speaking protocol versions older than 3 get instead of
+    /// [`ServerFrame::Error`]. The server doesn't send this directly.
+    Err(Id, String) = 1,
+    /// What clients speaking protocol version 1 get instead of [`ServerFrame::Broadcast`]. The
+    /// server doesn't send this directly.
+    BroadcastV1 {
+        sender: String,
+        msg: String,
+    } = 2,
+    /// What clients speaking protocol version 1 get instead of [`ServerFrame::Present`]. The
+    /// server doesn't send this directly.
+    PresentV1(String) = 3,
+    /// What clients speaking protocol version 1 get instead of [`ServerFrame::Login`]. The
+    /// server doesn't send this directly.
+    LoginV1(String) = 4,
+    /// What clients speaking protocol version 1 get instead of [`ServerFrame::Logout`]. The
+    /// server doesn't send this directly.
+    LogoutV1(String) = 5,
+    Direct {
+        sender: String,
+        msg: String,
+    } = 6,
+    /// Sent in response to a [`ClientFrameType::Msg`] instead of [`ServerFrame::Okay`]. Clients
+    /// speaking protocol version 1 still get an `Okay`.
+    Receipt {
+        id: Id,
+        msg_id: MsgId,
+        timestamp: Timestamp,
+    } = 7,
+    /// What clients speaking protocol versions older than 8 get instead of
+    /// [`ServerFrame::HistoryPage`]. The server doesn't send this directly.
+    HistoryPageV2 {
+        id: Id,
+        room: String,
+        msgs: Vec<HistoryMsgV2>,
+    } = 8,
+    /// Sent in response to a [`ClientFrameType::Hello`] with the protocol version both sides
+    /// should speak from now on.
+    Welcome {
+        id: Id,
+        protocol_version: u16,
+        features: Vec<String>,
+        limits: Limits,
+    } = 9,
+    Error {
+        id: Id,
+        code: ErrorCode,
+        msg: String,
+    } = 10,
+    /// Sent when a frame couldn't be decoded, so there's no request id to refer to.
+    ProtocolError {
+        code: ErrorCode,
+        msg: String,
+    } = 11,
+    /// The last frame the server sends before closing the connection.
+    Close {
+        reason: CloseReason,
+        msg: String,
+    } = 12,
+    /// Sent to the current members of the room when a message posted there was edited. Users
+    /// who only got the message because they were mentioned aren't told.
+    Edited {
+        room: String,
+        msg_id: MsgId,
+        msg: String,
+    } = 13,
+    /// Sent to the current members of the room when a message posted there was deleted. Users
+    /// who only got the message because they were mentioned aren't told.
+    Deleted {
+        room: String,
+        msg_id: MsgId,
+    } = 14,
+    /// All reactions to a message. Sent to the members of the room whenever they change, and
+    /// after history for messages that have any.
+    Reactions {
+        room: String,
+        msg_id: MsgId,
+        reactions: Vec<Reaction>,
+    } = 15,
+    /// Sent when a user starts typing in a room and when they stop (`typing == false`). Clients
+    /// should also consider the user done typing when they post a message to the room or leave it.
+    Typing {
+        room: String,
+        handle: String,
+        typing: bool,
+    } = 16,
+    /// The last message a user has read in a room. Sent when it changes and upon joining a room.
+    ReadUpTo {
+        room: String,
+        handle: String,
+        msg_id: MsgId,
+    } = 17,
+    /// Sent to the users sharing a room with someone whose status changed, and to the user
+    /// themselves. Statuses other than plain online are also sent upon joining a room, both to
+    /// the user joining (for every member) and to the members (for the user joining).
+    StatusChanged {
+        handle: String,
+        presence: Presence,
+        status: Option<String>,
+    } = 18,
+    /// Sent to the users sharing a room with someone who changed their handle, and to the user
+    /// themselves.
+    Renamed {
+        old: String,
+        new: String,
+    } = 19,
+    /// What clients speaking protocol version 4 get instead of [`ServerFrame::Broadcast`]. The
+    /// server doesn't send this directly.
+    BroadcastV4 {
+        msg_id: MsgId,
+        timestamp: Timestamp,
+        room: String,
+        sender: String,
+        msg: String,
+        reply_to: Option<MsgId>,
+    } = 20,
+    /// What clients speaking protocol versions older than 8 get instead of
+    /// [`ServerFrame::ThreadPage`]. The server doesn't send this directly.
+    ThreadPageV4 {
+        id: Id,
+        room: String,
+        msg_id: MsgId,
+        msgs: Vec<HistoryMsgV2>,
+    } = 21,
+    Broadcast {
+        msg_id: MsgId,
+        timestamp: Timestamp,
+        room: String,
+        sender: String,
+        msg: String,
+        /// The message that started the thread this is a reply in, if any.
+        reply_to: Option<MsgId>,
+        /// Handles of users mentioned in the message as `@handle`.
+        mentions: Vec<String>,
+    } = 22,
+    /// Sent to users mentioned in a message, whether they're in the room or not, and to users
+    /// an edit mentions for the first time.
+    Mentioned {
+        msg_id: MsgId,
+        room: String,
+        sender: String,
+        msg: String,
+    } = 23,
+    Pong(Id) = 24,
+    /// Sent when a user leaves a room, including when they log out.
+    Logout {
+        room: String,
+        handle: String,
+        reason: LogoutReason,
+    } = 25,
+    /// Sent to everyone right before the server closes their connection to shut down.
+    ServerShutdown {
+        reason: String,
+        /// How many seconds clients should wait before reconnecting, if the server expects to be
+        /// back.
+        reconnect_after: Option<u32>,
+    } = 26,
+    /// What clients speaking protocol versions 2 and 3 get instead of [`ServerFrame::Broadcast`].
+    /// The server doesn't send this directly.
+    BroadcastV2 {
+        msg_id: MsgId,
+        timestamp: Timestamp,
+        room: String,
+        sender: String,
+        msg: String,
+    } = 27,
+    /// Sent upon joining a room, once for every member.
+    Present {
+        room: String,
+        handle: String,
+    } = 28,
+    /// Sent to the members of a room when a user joins it, including when they log in.
+    Login {
+        room: String,
+        handle: String,
+    } = 29,
+    /// What clients speaking protocol versions older than 6 get instead of
+    /// [`ServerFrame::Logout`]. The server doesn't send this directly.
+    LogoutV2 {
+        room: String,
+        handle: String,
+    } = 30,
+    /// Sent in response to a [`ClientFrameType::History`]. Messages are ordered oldest first.
+    HistoryPage {
+        id: Id,
+        room: String,
+        msgs: Vec<HistoryMsg>,
+    } = 31,
+    /// Sent in response to a [`ClientFrameType::Thread`]. Replies are ordered oldest first.
+    ThreadPage {
+        id: Id,
+        room: String,
+        msg_id: MsgId,
+        msgs: Vec<HistoryMsg>,
+    } = 32,
+}
+
+impl<Id> ServerFrame<Id> {
+    pub fn error(id: Id, code: ErrorCode, msg: impl Into<String>) -> Self {
+        ServerFrame::Error {
+            id,
+            code,
+            msg: msg.into(),
+        }
+    }
+
+    /// Converts the request id in response frames.
+    pub fn map_id<T>(self, f: impl FnOnce(Id) -> T) -> ServerFrame<T> {
+        match self {
+            ServerFrame::Okay(id) => ServerFrame::Okay(f(id)),
+            ServerFrame::Err(id, msg) => ServerFrame::Err(f(id), msg),
+            ServerFrame::BroadcastV1 { sender, msg } => ServerFrame::BroadcastV1 { sender, msg },
+            ServerFrame::PresentV1(handle) => ServerFrame::PresentV1(handle),
+            ServerFrame::LoginV1(handle) => ServerFrame::LoginV1(handle),
+            ServerFrame::LogoutV1(handle) => ServerFrame::LogoutV1(handle),
+            ServerFrame::Direct { sender, msg } => ServerFrame::Direct { sender, msg },
+            ServerFrame::Receipt {
+                id,
+                msg_id,
+                timestamp,
+            } => ServerFrame::Receipt {
+                id: f(id),
+                msg_id,
+                timestamp,
+            },
+            ServerFrame::HistoryPageV2 { id, room, msgs } => ServerFrame::HistoryPageV2 {
+                id: f(id),
+                room,
+                msgs,
+            },
+            ServerFrame::Welcome {
+                id,
+                protocol_version,
+                features,
+                limits,
+            } => ServerFrame::Welcome {
+                id: f(id),
+                protocol_version,
+                features,
+                limits,
+            },
+            ServerFrame::Error { id, code, msg } => ServerFrame::Error {
+                id: f(id),
+                code,
+                msg,
+            },
+            ServerFrame::ProtocolError { code, msg } => ServerFrame::ProtocolError { code, msg },
+            ServerFrame::Close { reason, msg } => ServerFrame::Close { reason, msg },
+            ServerFrame::Edited { room, msg_id, msg } => ServerFrame::Edited { room, msg_id, msg },
+            ServerFrame::Deleted { room, msg_id } => ServerFrame::Deleted { room, msg_id },
+            ServerFrame::Reactions {
+                room,
+                msg_id,
+                reactions,
+            } => ServerFrame::Reactions {
+                room,
+                msg_id,
+                reactions,
+            },
+            ServerFrame::Typing {
+                room,
+                handle,
+                typing,
+            } => ServerFrame::Typing {
+                room,
+                handle,
+                typing,
+            },
+            ServerFrame::ReadUpTo {
+                room,
+                handle,
+                msg_id,
+            } => ServerFrame::ReadUpTo {
+                room,
+                handle,
+                msg_id,
+            },
+            ServerFrame::StatusChanged {
+                handle,
+                presence,
+                status,
+            } => ServerFrame::StatusChanged {
+                handle,
+                presence,
+                status,
+            },
+            ServerFrame::Renamed { old, new } => ServerFrame::Renamed { old, new },
+            ServerFrame::BroadcastV4 {
+                msg_id,
+                timestamp,
+                room,
+                sender,
+                msg,
+                reply_to,
+            } => ServerFrame::BroadcastV4 {
+                msg_id,
+                timestamp,
+                room,
+                sender,
+                msg,
+                reply_to,
+            },
+            ServerFrame::ThreadPageV4 {
+                id,
+                room,
+                msg_id,
+                msgs,
+            } => ServerFrame::ThreadPageV4 {
+                id: f(id),
+                room,
+                msg_id,
+                msgs,
+            },
+            ServerFrame::Broadcast {
+                msg_id,
+                timestamp,
+                room,
+                sender,
+                msg,
+                reply_to,
+                mentions,
+            } => ServerFrame::Broadcast {
+                msg_id,
+                timestamp,
+                room,
+                sender,
+                msg,
+                reply_to,
+                mentions,
+            },
+            ServerFrame::Mentioned {
+                msg_id,
+                room,
+                sender,
+                msg,
+            } => ServerFrame::Mentioned {
+                msg_id,
+                room,
+                sender,
+                msg,
+            },
+            ServerFrame::Pong(id) => ServerFrame::Pong(f(id)),
+            ServerFrame::Logout {
+                room,
+                handle,
+                reason,
+            } => ServerFrame::Logout {
+                room,
+                handle,
+                reason,
+            },
+            ServerFrame::ServerShutdown {
+                reason,
+                reconnect_after,
+            } => ServerFrame::ServerShutdown {
+                reason,
+                reconnect_after,
+            },
+            ServerFrame::BroadcastV2 {
+                msg_id,
+                timestamp,
+                room,
+                sender,
+                msg,
+            } => ServerFrame::BroadcastV2 {
+                msg_id,
+                timestamp,
+                room,
+                sender,
+                msg,
+            },
+            ServerFrame::Present { room, handle } => ServerFrame::Present { room, handle },
+            ServerFrame::Login { room, handle } => ServerFrame::Login { room, handle },
+            ServerFrame::LogoutV2 { room, handle } => ServerFrame::LogoutV2 { room, handle },
+            ServerFrame::HistoryPage { id, room, msgs } => ServerFrame::HistoryPage {
+                id: f(id),
+                room,
+                msgs,
+            },
+            ServerFrame::ThreadPage {
+                id,
+                room,
+                msg_id,
+                msgs,
+            } => ServerFrame::ThreadPage {
+                id: f(id),
+                room,
+                msg_id,
+                msgs,
+            },
+        }
+    }
+}
+
+/// Lets clients react to errors programmatically. The message sent alongside is meant for humans.
+///
+/// Clients can't decode codes they don't know, so adding one requires a new protocol version. Older
+/// clients get [`ErrorCode::Internal`] instead.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    BorshSerialize,
+    BorshDeserialize,
+    Serialize,
+    Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+#[repr(u8)]
+pub enum ErrorCode {
+    /// Something went wrong on the server's side.
+    Internal = 0,
+    UnsupportedVersion = 1,
+    HandleTaken = 2,
+    InvalidHandle = 3,
+    /// The recipient of a direct message isn't logged in, or is invisible (see
+    /// [`Presence::Invisible`]). Either way, the message isn't delivered.
+    NotLoggedIn = 4,
+    RateLimited = 5,
+    MsgTooLong = 6,
+    UnknownRoom = 7,
+    InvalidRoom = 8,
+    NotInRoom = 9,
+    AlreadyInRoom = 10,
+    Forbidden = 11,
+    /// The frame couldn't be decoded.
+    InvalidFrame = 12,
+    /// The frame isn't allowed at this point, e.g. a second login.
+    UnexpectedFrame = 13,
+    /// The message doesn't exist (anymore).
+    UnknownMsg = 14,
+    InvalidReaction = 15,
+    InvalidStatus = 16,
+}
+
+impl ErrorCode {
+    /// The protocol version that introduced the code.
+    pub fn since(self) -> u16 {
+        match self {
+            ErrorCode::InvalidFrame
+            | ErrorCode::UnexpectedFrame
+            | ErrorCode::UnknownMsg
+            | ErrorCode::InvalidReaction
+            | ErrorCode::InvalidStatus => 7,
+            _ => 3,
+        }
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, BorshSerialize, BorshDeserialize)]
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Hash,
+    BorshSerialize,
+    BorshDeserialize,
+    Serialize,
+    Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
 #[repr(u8)]
-pub enum ServerFrame {
-    Okay(u8) = 0,
-    Err(u8, String) = 1,
-    Broadcast { sender: String, msg: String } = 2,
-    Present(String) = 3,
-    Login(String) = 4,
-    Logout(String) = 5,
+pub enum Presence {
+    #[default]
+    Online = 0,
+    Away = 1,
+    Busy = 2,
+    /// The user appears offline to others: they're left out of member lists, the members of
+    /// their rooms are told they logged out, and direct messages to them are turned away.
+    Invisible = 3,
+}
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    BorshSerialize,
+    BorshDeserialize,
+    Serialize,
+    Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+#[repr(u8)]
+pub enum CloseReason {
+    TooManyErrors = 0,
+    /// Nothing was received from the client for too long.
+    IdleTimeout = 1,
+    /// The client didn't log in in time.
+    LoginTimeout = 2,
+}
+
+impl CloseReason {
+    /// The protocol version that introduced the reason.
+    pub fn since(self) -> u16 {
+        match self {
+            CloseReason::IdleTimeout => 6,
+            CloseReason::LoginTimeout => 9,
+            _ => 3,
+        }
+    }
+}
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    BorshSerialize,
+    BorshDeserialize,
+    Serialize,
+    Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+#[repr(u8)]
+pub enum LogoutReason {
+    /// The user left the room or logged out.
+    Left = 0,
+    /// The connection was closed without logging out first.
+    Disconnected = 1,
+    /// The user's connection went quiet for too long.
+    Timeout = 2,
+}
+
+#[derive(
+    Debug, Clone, PartialEq, Eq, Hash, BorshSerialize, BorshDeserialize, Serialize, Deserialize,
+)]
+pub struct Limits {
+    /// The longest message (in bytes) the server accepts.
+    pub max_msg_len: u32,
+    /// The most messages sent in a single [`ServerFrame::HistoryPage`].
+    pub history_page_limit: u32,
+}
+
+#[derive(
+    Debug, Clone, PartialEq, Eq, Hash, BorshSerialize, BorshDeserialize, Serialize, Deserialize,
+)]
+pub struct HistoryMsg {
+    pub msg_id: MsgId,
+    pub timestamp: Timestamp,
+    pub sender: String,
+    pub msg: String,
+    /// The message that started the thread this is a reply in, if any.
+    pub reply_to: Option<MsgId>,
+}
+
+/// What clients speaking protocol versions older than 8 get instead of [`HistoryMsg`].
+#[derive(
+    Debug, Clone, PartialEq, Eq, Hash, BorshSerialize, BorshDeserialize, Serialize, Deserialize,
+)]
+pub struct HistoryMsgV2 {
+    pub msg_id: MsgId,
+    pub timestamp: Timestamp,
+    pub sender: String,
+    pub msg: String,
+}
+
+impl From<HistoryMsg> for HistoryMsgV2 {
+    fn from(msg: HistoryMsg) -> Self {
+        HistoryMsgV2 {
+            msg_id: msg.msg_id,
+            timestamp: msg.timestamp,
+            sender: msg.sender,
+            msg: msg.msg,
+        }
+    }
+}
+
+#[derive(
+    Debug, Clone, PartialEq, Eq, Hash, BorshSerialize, BorshDeserialize, Serialize, Deserialize,
+)]
+pub struct Reaction {
+    pub emoji: String,
+    /// How many users reacted with this emoji.
+    pub count: u32,
 }
 
 #[derive(Debug, thiserror::Error)]
 pub enum DecodeError {
-    #[error("this server only accepts binary websocket frames")]
+    #[error("this server only accepts binary and text websocket frames")]
     InvalidWebsocketFrame,
+    #[error("a connection can't mix binary and JSON frames")]
+    MixedFormats,
     #[error("invalid mini-chat frame")]
     InvalidFrame,
+    #[error("frame too long")]
+    FrameTooLong,
 }
+
+#[derive(Debug, thiserror::Error)]
+#[error("frame could not be encoded")]
+pub struct EncodeError;
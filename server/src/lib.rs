@@ -1,45 +1,61 @@
+pub mod codec;
+pub mod config;
 mod context;
 pub mod frame;
 mod logic;
 pub mod protocol;
+mod server;
+mod store;
 mod stream;
+pub mod tls;
 
 use std::io::Error as IoError;
 
 use futures_util::{Future, Sink, Stream};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::TcpStream;
 
-use crate::context::Context;
+use crate::config::Config;
 use crate::frame::DecodeError;
 use crate::frame::{ClientFrame, ServerFrame};
-use crate::logic::handle_connection;
 
-/// The `stream_builder` callable is meant to split the [`TcpStream`] and decorate both the
-/// stream and sink. It can e.g. implement WebSocket as a transport for mini-chat frames.
-///
-/// This design should make it easier to, in the future, add other transports like raw TCP.
-pub async fn serve_tcp<F, FUT, SNK, STR>(addr: &str, stream_builder: F) -> Result<(), IoError>
+pub use server::{Peer, Server, Shutdown};
+
+/// Serves on a single address. See [`Server::listen_tcp`] for what `stream_builder` does, and
+/// [`Server`] for serving on several addresses at once.
+pub async fn serve_tcp<F, FUT, SNK, STR>(
+    addr: &str,
+    config: Config,
+    stream_builder: F,
+) -> Result<(), IoError>
 where
     F: Fn(TcpStream) -> FUT + Send + 'static,
-    FUT: Future<Output = Result<(SNK, STR), String>> + Send,
-    STR: Stream<Item = Result<ClientFrame, DecodeError>> + Send + Unpin + 'static,
+    FUT: Future<Output = Result<(SNK, STR), String>> + Send + 'static,
+    STR: Stream<Item = Result<Option<ClientFrame>, DecodeError>> + Send + Unpin + 'static,
     SNK: Sink<ServerFrame, Error = ()> + Send + Unpin + 'static,
 {
-    // if we ever serve on multiple ports (e.g. different transports), this context will need to be
-    // created outside this function and passed by parameter so that it can be shared
-    let ctx = Context::new();
-
-    let try_socket = TcpListener::bind(addr).await;
-    let listener = try_socket.expect("Failed to bind");
-    println!("Listening on: {}", addr);
-
-    let listener = listener;
-
-    while let Ok((tcp_stream, addr)) = listener.accept().await {
-        if let Ok((sink, stream)) = stream_builder(tcp_stream).await {
-            tokio::spawn(handle_connection(ctx.clone(), sink, stream, addr));
-        }
-    }
+    Server::new(config)?
+        .listen_tcp(addr, stream_builder)
+        .serve()
+        .await
+}
 
-    Ok(())
+/// Like [`serve_tcp`], but shuts down once `shutdown` resolves. New connections are no longer
+/// accepted, everyone connected is told about the shutdown and disconnected, and connections get
+/// up to [`Config::drain_timeout`] to wind down before they're left behind.
+pub async fn serve_tcp_until<F, FUT, SNK, STR>(
+    addr: &str,
+    config: Config,
+    stream_builder: F,
+    shutdown: impl Future<Output = Shutdown>,
+) -> Result<(), IoError>
+where
+    F: Fn(TcpStream) -> FUT + Send + 'static,
+    FUT: Future<Output = Result<(SNK, STR), String>> + Send + 'static,
+    STR: Stream<Item = Result<Option<ClientFrame>, DecodeError>> + Send + Unpin + 'static,
+    SNK: Sink<ServerFrame, Error = ()> + Send + Unpin + 'static,
+{
+    Server::new(config)?
+        .listen_tcp(addr, stream_builder)
+        .serve_until(shutdown)
+        .await
 }
@@ -1,47 +1,298 @@
-use std::net::SocketAddr;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use futures_util::future::Either;
+use futures_util::stream::Peekable;
 use futures_util::{future, pin_mut, Sink, SinkExt, Stream, StreamExt};
+use tokio::time::Instant;
 
-use crate::context::{Context, UserGuard, UserPool};
+use crate::context::{Context, Status, UserGuard, UserPool};
 use crate::frame::DecodeError;
-use crate::frame::{ClientFrame, ClientFrameType, ServerFrame};
-
-pub async fn handle_connection<SNK, STR>(
-    ctx: Context,
-    mut sink: SNK,
-    mut stream: STR,
-    addr: SocketAddr,
-) where
-    STR: Stream<Item = Result<ClientFrame, DecodeError>> + Unpin,
+use crate::frame::{
+    ClientFrame, ClientFrameType, CloseReason, ErrorCode, Limits, LogoutReason, MsgId, Presence,
+    RequestId, ServerFrame, Timestamp, DEFAULT_ROOM, FEATURES, MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION,
+};
+use crate::server::Peer;
+use crate::store::{Reactions, StoredMsg};
+
+pub async fn handle_connection<SNK, STR>(ctx: Context, mut sink: SNK, stream: STR, peer: Peer)
+where
+    STR: Stream<Item = Result<Option<ClientFrame>, DecodeError>> + Unpin,
     SNK: Sink<ServerFrame, Error = ()> + Unpin,
 {
-    println!("{} connected", &addr);
+    println!("{} connected", &peer);
 
-    fn on_logout(handle: &str, pool: &UserPool) {
-        pool.broadcast(ServerFrame::Logout(handle.to_string()));
-        println!("{} logged out", handle);
+    let shutdown = {
+        let session = handle_session(&ctx, &mut sink, stream, &peer);
+        let shutting_down = ctx.shutting_down();
+
+        pin_mut!(session, shutting_down);
+        match future::select(session, shutting_down).await {
+            Either::Left(_) => None,
+            Either::Right((shutdown, _)) => Some(shutdown),
+        }
+    };
+
+    if let Some(shutdown) = shutdown {
+        let reconnect_after = shutdown
+            .reconnect_after
+            .map(|after| u32::try_from(after.as_secs()).unwrap_or(u32::MAX));
+        let _ = sink
+            .send(ServerFrame::ServerShutdown {
+                reason: shutdown.reason,
+                reconnect_after,
+            })
+            .await;
+        let _ = sink.close().await;
+    }
+
+    println!("{} disconnected", &peer);
+}
+
+async fn handle_session<SNK, STR>(ctx: &Context, sink: &mut SNK, stream: STR, peer: &Peer)
+where
+    STR: Stream<Item = Result<Option<ClientFrame>, DecodeError>> + Unpin,
+    SNK: Sink<ServerFrame, Error = ()> + Unpin,
+{
+    // when anything was last heard from the client, frames or otherwise
+    let last_heard = Mutex::new(Instant::now());
+    let stream = stream.filter_map(|item| {
+        *last_heard.lock().unwrap() = Instant::now();
+        future::ready(item.transpose())
+    });
+    let mut stream = stream.peekable();
+    // saying hello counts towards the time a client has to log in
+    let mut login_deadline = Instant::now() + ctx.config().login_timeout;
+    let hello = tokio::time::timeout_at(login_deadline, handle_hello(ctx, sink, &mut stream));
+    match hello.await {
+        Ok(Ok(Some(version))) => println!("{} speaks protocol version {}", peer, version),
+        Ok(Ok(None)) => println!("{} didn't say hello", peer),
+        Ok(Err(())) => return,
+        Err(_) => {
+            let _ = close_for_login_timeout(sink).await;
+            return;
+        }
     }
 
-    while let Ok(mut user) = handle_login(&ctx, &mut sink, &mut stream, on_logout).await {
-        let rx = user.take_rx().unwrap();
-        let handle_frames = handle_chat_msgs(&ctx, user, &mut stream);
-        let receive_from_others = rx.map(Ok).forward(&mut sink);
+    // how the current session ended, for the logout broadcasts
+    let reason = Mutex::new(LogoutReason::Disconnected);
+    let on_logout = |handle: &str, users: &UserPool| {
+        let invisible = users
+            .status(handle)
+            .is_some_and(|status| status.presence == Presence::Invisible);
+        ctx.read_markers().forget_user(handle);
+        for room in ctx.rooms().leave_all(handle) {
+            if !ctx.rooms().exists(&room) {
+                ctx.read_markers().forget_room(&room);
+            }
+            if invisible {
+                // as far as others are concerned, they're gone already
+                continue;
+            }
+            ctx.broadcast_to_room(
+                &room,
+                ServerFrame::Logout {
+                    room: room.clone(),
+                    handle: handle.to_string(),
+                    reason: *reason.lock().unwrap(),
+                },
+            );
+        }
+        println!("{} logged out", handle);
+    };
+
+    let mut errors = ErrorBudget::new(ctx.config().max_protocol_errors);
+
+    while let Ok(mut user) = handle_login(
+        ctx,
+        sink,
+        &mut stream,
+        &mut errors,
+        login_deadline,
+        on_logout,
+    )
+    .await
+    {
+        let mut rx = user.take_rx().unwrap();
+
+        let end = {
+            let handle_frames =
+                handle_chat_msgs(ctx, &mut user, &mut stream, &mut errors, &last_heard);
+            let receive_from_others = (&mut rx).map(Ok).forward(&mut *sink);
+
+            pin_mut!(handle_frames, receive_from_others);
+            match future::select(handle_frames, receive_from_others).await {
+                Either::Left((end, _)) => end,
+                Either::Right(_) => SessionEnd::Disconnected,
+            }
+        };
+
+        *reason.lock().unwrap() = match end {
+            SessionEnd::Logout(_) => LogoutReason::Left,
+            SessionEnd::TimedOut => LogoutReason::Timeout,
+            SessionEnd::Disconnected | SessionEnd::TooManyErrors => LogoutReason::Disconnected,
+        };
+        drop(user);
+
+        // The user is logged out now. Whatever was still queued for them should arrive before
+        // the response to the logout.
+        while let Ok(Some(frame)) = rx.try_next() {
+            let _ = sink.send(frame).await;
+        }
 
-        pin_mut!(handle_frames, receive_from_others);
-        let x = future::select(handle_frames, receive_from_others).await;
-        if let Either::Left((Some(id), _)) = x {
-            let _ = sink.send(ServerFrame::Okay(id)).await;
+        match end {
+            SessionEnd::Logout(id) => {
+                let _ = sink.send(ServerFrame::Okay(id)).await;
+                login_deadline = Instant::now() + ctx.config().login_timeout;
+            }
+            SessionEnd::Disconnected => break,
+            SessionEnd::TooManyErrors => {
+                let _ = close_for_errors(sink).await;
+                break;
+            }
+            SessionEnd::TimedOut => {
+                let _ = close(
+                    sink,
+                    CloseReason::IdleTimeout,
+                    "nothing received for too long",
+                )
+                .await;
+                break;
+            }
         }
     }
+}
+
+enum SessionEnd {
+    Logout(RequestId),
+    Disconnected,
+    TooManyErrors,
+    TimedOut,
+}
+
+/// Counts down protocol errors a client is allowed to make before being disconnected.
+struct ErrorBudget(usize);
+
+impl ErrorBudget {
+    fn new(max_errors: usize) -> Self {
+        Self(max_errors)
+    }
+
+    /// Returns `false` once the client ran out of errors to make.
+    fn spend(&mut self) -> bool {
+        self.0 = self.0.saturating_sub(1);
+        self.0 > 0
+    }
+}
+
+/// The error to respond with when a frame couldn't be decoded.
+fn protocol_error(err: DecodeError) -> ServerFrame {
+    ServerFrame::ProtocolError {
+        code: ErrorCode::InvalidFrame,
+        msg: err.to_string(),
+    }
+}
+
+async fn close_for_errors<SNK>(sink: &mut SNK) -> Result<(), ()>
+where
+    SNK: Sink<ServerFrame, Error = ()> + Unpin,
+{
+    close(sink, CloseReason::TooManyErrors, "too many protocol errors").await
+}
+
+async fn close_for_login_timeout<SNK>(sink: &mut SNK) -> Result<(), ()>
+where
+    SNK: Sink<ServerFrame, Error = ()> + Unpin,
+{
+    close(sink, CloseReason::LoginTimeout, "didn't log in in time").await
+}
 
-    println!("{} disconnected", &addr);
+/// Tells the client why the connection is about to be closed, then closes it.
+async fn close<SNK>(sink: &mut SNK, reason: CloseReason, msg: &str) -> Result<(), ()>
+where
+    SNK: Sink<ServerFrame, Error = ()> + Unpin,
+{
+    sink.send(ServerFrame::Close {
+        reason,
+        msg: msg.to_string(),
+    })
+    .await?;
+    sink.close().await
+}
+
+/// Handles the optional [`ClientFrameType::Hello`] and returns the protocol version negotiated
+/// for this connection, if any. Without one, it's up to the codec (see [`Codec`]).
+///
+/// [`Codec`]: crate::codec::Codec
+async fn handle_hello<SNK, STR>(
+    ctx: &Context,
+    sink: &mut SNK,
+    stream: &mut Peekable<STR>,
+) -> Result<Option<u16>, ()>
+where
+    STR: Stream<Item = Result<ClientFrame, DecodeError>> + Unpin,
+    SNK: Sink<ServerFrame, Error = ()> + Unpin,
+{
+    let hello = Pin::new(stream)
+        .next_if(|frame| {
+            matches!(
+                frame,
+                Ok(ClientFrame {
+                    data: ClientFrameType::Hello { .. },
+                    ..
+                })
+            )
+        })
+        .await;
+
+    // no client capabilities are defined yet
+    let Some(Ok(ClientFrame {
+        id,
+        data: ClientFrameType::Hello {
+            protocol_version, ..
+        },
+    })) = hello
+    else {
+        return Ok(None);
+    };
+
+    if protocol_version < MIN_PROTOCOL_VERSION {
+        sink.send(ServerFrame::error(
+            id,
+            ErrorCode::UnsupportedVersion,
+            format!(
+                "unsupported protocol version {}, this server speaks versions {} to {}",
+                protocol_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ),
+        ))
+        .await?;
+        return Err(());
+    }
+
+    let protocol_version = protocol_version.min(PROTOCOL_VERSION);
+    sink.send(ServerFrame::Welcome {
+        id,
+        protocol_version,
+        features: FEATURES.iter().map(ToString::to_string).collect(),
+        limits: Limits {
+            max_msg_len: ctx.config().max_msg_len as u32,
+            history_page_limit: ctx.config().history_page_limit as u32,
+        },
+    })
+    .await?;
+
+    Ok(Some(protocol_version))
 }
 
 async fn handle_login<'c, F, SNK, STR>(
     ctx: &'c Context,
     sink: &mut SNK,
     stream: &mut STR,
+    errors: &mut ErrorBudget,
+    deadline: Instant,
     on_logout: F,
 ) -> Result<UserGuard<'c, F>, ()>
 where
@@ -49,71 +300,971 @@ where
     SNK: Sink<ServerFrame, Error = ()> + Unpin,
     F: Fn(&str, &UserPool),
 {
-    if let Some(Ok(ClientFrame {
-        id,
-        data: ClientFrameType::Login(handle),
-    })) = stream.next().await
-    {
-        match ctx.users().register_user_with_callback(handle, on_logout) {
-            Some(user) => {
-                sink.send(ServerFrame::Okay(id)).await?;
+    let (id, handle) = loop {
+        let Ok(frame) = tokio::time::timeout_at(deadline, stream.next()).await else {
+            close_for_login_timeout(sink).await?;
+            return Err(());
+        };
+        let error = match frame.ok_or(())? {
+            Ok(ClientFrame {
+                id,
+                data: ClientFrameType::Login(handle),
+            }) => break (id, handle),
+            Ok(ClientFrame {
+                id,
+                data: ClientFrameType::Ping,
+            }) => {
+                sink.send(ServerFrame::Pong(id)).await?;
+                continue;
+            }
+            Ok(ClientFrame { id, .. }) => {
+                ServerFrame::error(id, ErrorCode::UnexpectedFrame, "log in first")
+            }
+            Err(e) => protocol_error(e),
+        };
 
-                ctx.users()
-                    .broadcast_except(user.handle(), ServerFrame::Login(user.handle().to_string()));
+        sink.send(error).await?;
+        if !errors.spend() {
+            close_for_errors(sink).await?;
+            return Err(());
+        }
+    };
 
-                for peer_handle in ctx.users().users() {
-                    let _ = sink
-                        .send(ServerFrame::Present(peer_handle.to_string()))
-                        .await;
-                }
+    if !is_valid_handle(&handle, ctx.config().max_handle_len) {
+        sink.send(invalid_handle(ctx, id)).await?;
+        return Err(());
+    }
 
-                println!("{} logged in", user.handle());
+    match ctx.users().register_user_with_callback(handle, on_logout) {
+        Some(user) => {
+            sink.send(ServerFrame::Okay(id)).await?;
 
-                Ok(user)
-            }
-            None => {
-                sink.send(ServerFrame::Err(id, "handle taken".to_string()))
-                    .await?;
-                Err(())
-            }
+            println!("{} logged in", user.handle());
+
+            join_room(ctx, &user, DEFAULT_ROOM).await;
+
+            Ok(user)
+        }
+        None => {
+            sink.send(ServerFrame::error(
+                id,
+                ErrorCode::HandleTaken,
+                "handle taken",
+            ))
+            .await?;
+            Err(())
         }
-    } else {
-        Err(())
     }
 }
 
 async fn handle_chat_msgs<STR, F>(
     cx: &Context,
-    user: UserGuard<'_, F>,
+    user: &mut UserGuard<'_, F>,
     stream: &mut STR,
-) -> Option<u8>
+    errors: &mut ErrorBudget,
+    last_heard: &Mutex<Instant>,
+) -> SessionEnd
 where
     STR: Stream<Item = Result<ClientFrame, DecodeError>> + Unpin,
     F: Fn(&str, &UserPool),
 {
-    while let Some(frame) = stream.next().await {
-        if let Ok(ClientFrame { data: request, id }) = frame {
-            match request {
-                ClientFrameType::Msg(msg) => {
-                    let receipt = ServerFrame::Okay(id);
-
-                    let _ = user.send(receipt);
+    let mut typing = Typing::default();
+    let mut idle = Idle::new(cx.config().away_after);
+    // whether the user is away because they went idle, rather than by choice
+    let mut auto_away = false;
+    let timeout = || *last_heard.lock().unwrap() + cx.config().idle_timeout;
 
-                    cx.users().broadcast_except(
+    loop {
+        let deadline = typing
+            .next_expiry()
+            .into_iter()
+            .chain(idle.deadline())
+            .fold(timeout(), Instant::min);
+        let frame = match tokio::time::timeout_at(deadline, stream.next()).await {
+            Ok(frame) => frame,
+            Err(_) => {
+                let now = Instant::now();
+                if timeout() <= now {
+                    return SessionEnd::TimedOut;
+                }
+                for room in typing.expire(now) {
+                    cx.broadcast_to_room_except(
+                        &room,
                         user.handle(),
-                        ServerFrame::Broadcast {
-                            sender: user.handle().to_string(),
-                            msg,
+                        ServerFrame::Typing {
+                            room: room.clone(),
+                            handle: user.handle().to_string(),
+                            typing: false,
                         },
                     );
                 }
-                ClientFrameType::Logout => {
-                    return Some(id);
+                if idle.check(now) {
+                    let status = cx.users().status(user.handle());
+                    if let Some(status) = status.filter(|s| s.presence == Presence::Online) {
+                        auto_away = true;
+                        let away = Status {
+                            presence: Presence::Away,
+                            ..status
+                        };
+                        change_status(cx, user.handle(), away);
+                    }
+                }
+                continue;
+            }
+        };
+        let Some(frame) = frame else {
+            break;
+        };
+
+        // pinging keeps the connection alive, but doesn't mean the user is back
+        let ping = matches!(
+            frame,
+            Ok(ClientFrame {
+                data: ClientFrameType::Ping,
+                ..
+            })
+        );
+        if !ping && idle.active(Instant::now()) && std::mem::take(&mut auto_away) {
+            let status = cx.users().status(user.handle());
+            if let Some(status) = status.filter(|s| s.presence == Presence::Away) {
+                let online = Status {
+                    presence: Presence::Online,
+                    ..status
+                };
+                change_status(cx, user.handle(), online);
+            }
+        }
+
+        let error = match frame {
+            Ok(ClientFrame {
+                id,
+                data: ClientFrameType::Login(_),
+            }) => ServerFrame::error(id, ErrorCode::UnexpectedFrame, "already logged in"),
+            Ok(ClientFrame {
+                id,
+                data: ClientFrameType::Hello { .. },
+            }) => ServerFrame::error(
+                id,
+                ErrorCode::UnexpectedFrame,
+                "hello has to be the first frame",
+            ),
+            Err(e) => protocol_error(e),
+            Ok(ClientFrame { data: request, id }) => {
+                match request {
+                    ClientFrameType::Msg { room, msg } => {
+                        if !cx.rooms().is_member(&room, user.handle()) {
+                            let _ = user.send(not_in_room(cx, id, &room));
+                            continue;
+                        }
+
+                        if post_msg(cx, user, id, room.clone(), msg, None).await {
+                            typing.stop(&room);
+                        }
+                    }
+                    ClientFrameType::Reply { reply_to, msg } => {
+                        let Some(parent) = cx.store().get(reply_to).await else {
+                            let _ = user.send(unknown_msg(id));
+                            continue;
+                        };
+                        if !cx.rooms().is_member(&parent.room, user.handle()) {
+                            let _ = user.send(not_in_room(cx, id, &parent.room));
+                            continue;
+                        }
+
+                        let thread = parent.reply_to.unwrap_or(parent.id);
+                        let room = parent.room;
+                        if post_msg(cx, user, id, room.clone(), msg, Some(thread)).await {
+                            typing.stop(&room);
+                        }
+                    }
+                    ClientFrameType::Thread {
+                        msg_id,
+                        before,
+                        limit,
+                    } => {
+                        let Some(msg) = cx.store().get(msg_id).await else {
+                            let _ = user.send(unknown_msg(id));
+                            continue;
+                        };
+                        if !cx.rooms().is_member(&msg.room, user.handle()) {
+                            let _ = user.send(not_in_room(cx, id, &msg.room));
+                            continue;
+                        }
+
+                        let limit = (limit as usize).min(cx.config().history_page_limit);
+                        let msgs = cx.store().replies(msg_id, before, limit).await;
+                        let reactions: Vec<_> = msgs.iter().filter_map(reactions_of).collect();
+
+                        let _ = user.send(ServerFrame::ThreadPage {
+                            id,
+                            room: msg.room,
+                            msg_id,
+                            msgs: msgs.into_iter().map(Into::into).collect(),
+                        });
+                        for frame in reactions {
+                            let _ = user.send(frame);
+                        }
+                    }
+                    ClientFrameType::Join(room) => {
+                        if !is_valid_room(&room, cx.config().max_room_len) {
+                            let _ = user.send(invalid_room(cx, id));
+                            continue;
+                        }
+                        if cx.rooms().is_member(&room, user.handle()) {
+                            let _ = user.send(ServerFrame::error(
+                                id,
+                                ErrorCode::AlreadyInRoom,
+                                "already in room",
+                            ));
+                            continue;
+                        }
+
+                        let _ = user.send(ServerFrame::Okay(id));
+                        join_room(cx, user, &room).await;
+                    }
+                    ClientFrameType::Leave(room) => {
+                        if !cx.rooms().leave(&room, user.handle()) {
+                            let _ = user.send(not_in_room(cx, id, &room));
+                            continue;
+                        }
+                        if !cx.rooms().exists(&room) {
+                            cx.read_markers().forget_room(&room);
+                        }
+                        typing.stop(&room);
+
+                        let _ = user.send(ServerFrame::Okay(id));
+                        cx.broadcast_to_room(
+                            &room,
+                            ServerFrame::Logout {
+                                room: room.clone(),
+                                handle: user.handle().to_string(),
+                                reason: LogoutReason::Left,
+                            },
+                        );
+                    }
+                    ClientFrameType::DirectMsg { to, msg } => {
+                        if msg.len() > cx.config().max_msg_len {
+                            let _ = user.send(ServerFrame::error(
+                                id,
+                                ErrorCode::MsgTooLong,
+                                "message too long",
+                            ));
+                            continue;
+                        }
+
+                        let frame = ServerFrame::Direct {
+                            sender: user.handle().to_string(),
+                            msg,
+                        };
+
+                        // invisible users look like they're offline, so they don't get the
+                        // message either - otherwise the sender would send it again later
+                        let invisible = cx
+                            .users()
+                            .status(&to)
+                            .is_some_and(|s| s.presence == Presence::Invisible);
+                        if !invisible && cx.users().send(&to, frame) {
+                            let _ = user.send(ServerFrame::Okay(id));
+                        } else {
+                            let _ = user.send(ServerFrame::error(
+                                id,
+                                ErrorCode::NotLoggedIn,
+                                format!("{} is not logged in", to),
+                            ));
+                        }
+                    }
+                    ClientFrameType::History {
+                        room,
+                        before,
+                        limit,
+                    } => {
+                        if !cx.rooms().is_member(&room, user.handle()) {
+                            let _ = user.send(not_in_room(cx, id, &room));
+                            continue;
+                        }
+
+                        let limit = (limit as usize).min(cx.config().history_page_limit);
+                        let msgs = cx.store().history(&room, before, limit).await;
+                        let reactions: Vec<_> = msgs.iter().filter_map(reactions_of).collect();
+
+                        let _ = user.send(ServerFrame::HistoryPage {
+                            id,
+                            room,
+                            msgs: msgs.into_iter().map(Into::into).collect(),
+                        });
+                        for frame in reactions {
+                            let _ = user.send(frame);
+                        }
+                    }
+                    ClientFrameType::Edit { msg_id, new_text } => {
+                        if new_text.len() > cx.config().max_msg_len {
+                            let _ = user.send(ServerFrame::error(
+                                id,
+                                ErrorCode::MsgTooLong,
+                                "message too long",
+                            ));
+                            continue;
+                        }
+                        let msg = match own_msg(cx, id, msg_id, user.handle()).await {
+                            Ok(msg) => msg,
+                            Err(error) => {
+                                let _ = user.send(error);
+                                continue;
+                            }
+                        };
+
+                        let mentioned = mentions(cx, &new_text);
+                        let stored_mentions = visible(cx, &mentioned);
+                        match cx
+                            .store()
+                            .edit(msg_id, new_text.clone(), stored_mentions)
+                            .await
+                        {
+                            Ok(true) => {}
+                            Ok(false) => {
+                                let _ = user.send(unknown_msg(id));
+                                continue;
+                            }
+                            Err(e) => {
+                                println!("failed to edit message {}: {}", msg_id, e);
+                                let _ = user.send(ServerFrame::error(
+                                    id,
+                                    ErrorCode::Internal,
+                                    "message not edited",
+                                ));
+                                continue;
+                            }
+                        }
+
+                        let _ = user.send(ServerFrame::Okay(id));
+                        // users who were already mentioned heard about the message before
+                        let newly_mentioned: Vec<_> = mentioned
+                            .into_iter()
+                            .filter(|handle| !msg.mentions.contains(handle))
+                            .collect();
+                        let edited = StoredMsg {
+                            msg: new_text.clone(),
+                            ..msg
+                        };
+                        notify_mentioned(cx, &edited, &newly_mentioned);
+                        cx.broadcast_to_room_except(
+                            &edited.room,
+                            user.handle(),
+                            ServerFrame::Edited {
+                                room: edited.room.clone(),
+                                msg_id,
+                                msg: new_text,
+                            },
+                        );
+                    }
+                    ClientFrameType::Delete { msg_id } => {
+                        let msg = match own_msg(cx, id, msg_id, user.handle()).await {
+                            Ok(msg) => msg,
+                            Err(error) => {
+                                let _ = user.send(error);
+                                continue;
+                            }
+                        };
+
+                        match cx.store().delete(msg_id).await {
+                            Ok(true) => {}
+                            Ok(false) => {
+                                let _ = user.send(unknown_msg(id));
+                                continue;
+                            }
+                            Err(e) => {
+                                println!("failed to delete message {}: {}", msg_id, e);
+                                let _ = user.send(ServerFrame::error(
+                                    id,
+                                    ErrorCode::Internal,
+                                    "message not deleted",
+                                ));
+                                continue;
+                            }
+                        }
+
+                        let _ = user.send(ServerFrame::Okay(id));
+                        cx.broadcast_to_room_except(
+                            &msg.room,
+                            user.handle(),
+                            ServerFrame::Deleted {
+                                room: msg.room.clone(),
+                                msg_id,
+                            },
+                        );
+                    }
+                    ClientFrameType::React { msg_id, emoji, on } => {
+                        if !is_valid_reaction(&emoji, cx.config().max_reaction_len) {
+                            let _ = user.send(ServerFrame::error(
+                                id,
+                                ErrorCode::InvalidReaction,
+                                "invalid reaction",
+                            ));
+                            continue;
+                        }
+                        let room = match cx.store().get(msg_id).await {
+                            Some(msg) => msg.room,
+                            None => {
+                                let _ = user.send(unknown_msg(id));
+                                continue;
+                            }
+                        };
+                        if !cx.rooms().is_member(&room, user.handle()) {
+                            let _ = user.send(not_in_room(cx, id, &room));
+                            continue;
+                        }
+
+                        let reactions = match cx
+                            .store()
+                            .react(msg_id, emoji, user.handle().to_string(), on)
+                            .await
+                        {
+                            Ok(Some(reactions)) => reactions,
+                            Ok(None) => {
+                                let _ = user.send(unknown_msg(id));
+                                continue;
+                            }
+                            Err(e) => {
+                                println!("failed to store reaction to {}: {}", msg_id, e);
+                                let _ = user.send(ServerFrame::error(
+                                    id,
+                                    ErrorCode::Internal,
+                                    "reaction not stored",
+                                ));
+                                continue;
+                            }
+                        };
+
+                        let _ = user.send(ServerFrame::Okay(id));
+                        cx.broadcast_to_room(
+                            &room,
+                            ServerFrame::Reactions {
+                                room: room.clone(),
+                                msg_id,
+                                reactions: reactions.counts(),
+                            },
+                        );
+                    }
+                    ClientFrameType::Typing { room } => {
+                        if !cx.rooms().is_member(&room, user.handle()) {
+                            continue;
+                        }
+
+                        let expiry = Instant::now() + cx.config().typing_timeout;
+                        if typing.start(room.clone(), expiry) {
+                            cx.broadcast_to_room_except(
+                                &room,
+                                user.handle(),
+                                ServerFrame::Typing {
+                                    room: room.clone(),
+                                    handle: user.handle().to_string(),
+                                    typing: true,
+                                },
+                            );
+                        }
+                    }
+                    ClientFrameType::MarkRead(msg_id) => {
+                        let room = match cx.store().get(msg_id).await {
+                            Some(msg) => msg.room,
+                            None => {
+                                let _ = user.send(unknown_msg(id));
+                                continue;
+                            }
+                        };
+                        if !cx.rooms().is_member(&room, user.handle()) {
+                            let _ = user.send(not_in_room(cx, id, &room));
+                            continue;
+                        }
+
+                        let _ = user.send(ServerFrame::Okay(id));
+                        if cx.read_markers().mark(&room, user.handle(), msg_id) {
+                            cx.broadcast_to_room_except(
+                                &room,
+                                user.handle(),
+                                ServerFrame::ReadUpTo {
+                                    room: room.clone(),
+                                    handle: user.handle().to_string(),
+                                    msg_id,
+                                },
+                            );
+                        }
+                    }
+                    ClientFrameType::SetStatus { presence, status } => {
+                        if status
+                            .as_ref()
+                            .is_some_and(|text| text.len() > cx.config().max_status_len)
+                        {
+                            let _ = user.send(ServerFrame::error(
+                                id,
+                                ErrorCode::InvalidStatus,
+                                "status too long",
+                            ));
+                            continue;
+                        }
+
+                        let _ = user.send(ServerFrame::Okay(id));
+                        auto_away = false;
+                        change_status(
+                            cx,
+                            user.handle(),
+                            Status {
+                                presence,
+                                text: status,
+                            },
+                        );
+                    }
+                    ClientFrameType::Rename(new) => {
+                        if new == user.handle() {
+                            let _ = user.send(ServerFrame::error(
+                                id,
+                                ErrorCode::InvalidHandle,
+                                "that's already your handle",
+                            ));
+                            continue;
+                        }
+                        if !is_valid_handle(&new, cx.config().max_handle_len) {
+                            let _ = user.send(invalid_handle(cx, id));
+                            continue;
+                        }
+
+                        let old = user.handle().to_string();
+                        let renamed = user
+                            .rename(new.clone(), async |old, new| {
+                                cx.rooms().rename(old, new);
+                                cx.read_markers().rename(old, new);
+                                if let Err(e) = cx.store().rename(old, new).await {
+                                    println!(
+                                        "failed to move {}'s reactions to {}: {}",
+                                        old, new, e
+                                    );
+                                }
+                            })
+                            .await;
+                        if !renamed {
+                            let _ = user.send(ServerFrame::error(
+                                id,
+                                ErrorCode::HandleTaken,
+                                "handle taken",
+                            ));
+                            continue;
+                        }
+                        println!("{} is now {}", old, new);
+
+                        let _ = user.send(ServerFrame::Okay(id));
+                        let frame = ServerFrame::Renamed {
+                            old: old.clone(),
+                            new,
+                        };
+                        let invisible = cx
+                            .users()
+                            .status(user.handle())
+                            .is_some_and(|s| s.presence == Presence::Invisible);
+                        if !invisible {
+                            cx.broadcast_to_peers(user.handle(), frame.clone());
+                        }
+                        let _ = user.send(frame);
+                        // the typing indicator started under the old handle would never stop
+                        for room in typing.rooms() {
+                            for (handle, typing) in [(&*old, false), (user.handle(), true)] {
+                                cx.broadcast_to_room_except(
+                                    room,
+                                    user.handle(),
+                                    ServerFrame::Typing {
+                                        room: room.clone(),
+                                        handle: handle.to_string(),
+                                        typing,
+                                    },
+                                );
+                            }
+                        }
+                    }
+                    ClientFrameType::Ping => {
+                        let _ = user.send(ServerFrame::Pong(id));
+                    }
+                    ClientFrameType::Logout => {
+                        return SessionEnd::Logout(id);
+                    }
+                    _ => {}
                 }
-                _ => {}
+                continue;
+            }
+        };
+
+        let _ = user.send(error);
+        if !errors.spend() {
+            return SessionEnd::TooManyErrors;
+        }
+    }
+
+    SessionEnd::Disconnected
+}
+
+/// The rooms a user is typing in and when they're no longer considered typing there. Repeated
+/// notifications only push the expiry back, so other users only hear about it once.
+#[derive(Default)]
+struct Typing(HashMap<String, Instant>);
+
+impl Typing {
+    /// Returns `true` if the user wasn't typing in the room yet.
+    fn start(&mut self, room: String, expiry: Instant) -> bool {
+        self.0.insert(room, expiry).is_none()
+    }
+
+    fn stop(&mut self, room: &str) {
+        self.0.remove(room);
+    }
+
+    fn rooms(&self) -> impl Iterator<Item = &String> {
+        self.0.keys()
+    }
+
+    fn next_expiry(&self) -> Option<Instant> {
+        self.0.values().min().copied()
+    }
+
+    /// Returns the rooms the user stopped typing in.
+    fn expire(&mut self, now: Instant) -> Vec<String> {
+        let mut expired = Vec::new();
+        self.0.retain(|room, expiry| {
+            if *expiry <= now {
+                expired.push(room.clone());
+            }
+            *expiry > now
+        });
+        expired
+    }
+}
+
+/// Stores the message and sends it to the room. Returns `false` if the user got an error instead
+/// of a receipt.
+async fn post_msg<F>(
+    cx: &Context,
+    user: &UserGuard<'_, F>,
+    id: RequestId,
+    room: String,
+    msg: String,
+    reply_to: Option<MsgId>,
+) -> bool
+where
+    F: Fn(&str, &UserPool),
+{
+    if msg.len() > cx.config().max_msg_len {
+        let _ = user.send(ServerFrame::error(
+            id,
+            ErrorCode::MsgTooLong,
+            "message too long",
+        ));
+        return false;
+    }
+
+    let mentioned = mentions(cx, &msg);
+    let msg = StoredMsg {
+        id: cx.next_msg_id(),
+        timestamp: utc_now(),
+        room,
+        sender: user.handle().to_string(),
+        mentions: visible(cx, &mentioned),
+        msg,
+        reply_to,
+        reactions: Reactions::default(),
+    };
+
+    if let Err(e) = cx.store().push(msg.clone()).await {
+        println!("failed to store message {}: {}", msg.id, e);
+        let _ = user.send(ServerFrame::error(
+            id,
+            ErrorCode::Internal,
+            "message not sent",
+        ));
+        return false;
+    }
+
+    let receipt = ServerFrame::Receipt {
+        id,
+        msg_id: msg.id,
+        timestamp: msg.timestamp,
+    };
+
+    let _ = user.send(receipt);
+
+    notify_mentioned(cx, &msg, &mentioned);
+
+    let room = msg.room.clone();
+    cx.broadcast_to_room_except(&room, user.handle(), msg.into());
+    true
+}
+
+/// Sends a [`ServerFrame::Mentioned`] to the users mentioned in the message, except the sender.
+fn notify_mentioned(cx: &Context, msg: &StoredMsg, mentioned: &[String]) {
+    for handle in mentioned.iter().filter(|h| **h != msg.sender) {
+        cx.users().send(
+            handle,
+            ServerFrame::Mentioned {
+                msg_id: msg.id,
+                room: msg.room.clone(),
+                sender: msg.sender.clone(),
+                msg: msg.msg.clone(),
+            },
+        );
+    }
+}
+
+/// The mentions that end up in the message. Invisible users can still be mentioned, but the
+/// sender shouldn't find out they're here.
+fn visible(cx: &Context, mentioned: &[String]) -> Vec<String> {
+    mentioned
+        .iter()
+        .filter(|handle| {
+            cx.users()
+                .status(handle)
+                .is_some_and(|s| s.presence != Presence::Invisible)
+        })
+        .cloned()
+        .collect()
+}
+
+/// Logged in users mentioned in the message as `@handle`, in order. Punctuation right after a
+/// mention is ignored, unless it's part of the handle. Anything else is just text.
+fn mentions(cx: &Context, msg: &str) -> Vec<String> {
+    let mut mentions = Vec::new();
+    for word in msg.split_whitespace() {
+        let Some(handle) = word.strip_prefix('@') else {
+            continue;
+        };
+        let trimmed = handle.trim_end_matches(|c: char| c.is_ascii_punctuation());
+        let found = [handle, trimmed]
+            .into_iter()
+            .find(|h| cx.users().status(h).is_some());
+
+        if let Some(handle) = found {
+            if !mentions.iter().any(|m| m == handle) {
+                mentions.push(handle.to_string());
             }
         }
     }
+    mentions
+}
+
+/// Tracks when the user was last active, so they can be set away once they go idle.
+struct Idle {
+    away_after: Duration,
+    last_active: Instant,
+    idle: bool,
+}
+
+impl Idle {
+    fn new(away_after: Duration) -> Self {
+        Self {
+            away_after,
+            last_active: Instant::now(),
+            idle: false,
+        }
+    }
+
+    /// When the user goes idle, unless they already are.
+    fn deadline(&self) -> Option<Instant> {
+        (!self.idle).then(|| self.last_active + self.away_after)
+    }
+
+    /// Returns `true` if the user just went idle.
+    fn check(&mut self, now: Instant) -> bool {
+        if self.deadline().is_some_and(|deadline| deadline <= now) {
+            self.idle = true;
+            return true;
+        }
+        false
+    }
+
+    /// Returns `true` if the user was idle until now.
+    fn active(&mut self, now: Instant) -> bool {
+        self.last_active = now;
+        std::mem::take(&mut self.idle)
+    }
+}
+
+/// Updates the user's status and lets them and everyone sharing a room with them know. Going
+/// invisible looks like logging out to others, and coming back like logging in.
+fn change_status(cx: &Context, handle: &str, status: Status) {
+    let Some(old) = cx.users().set_status(handle, status.clone()) else {
+        return;
+    };
+    let was_invisible = old.presence == Presence::Invisible;
+    let invisible = status.presence == Presence::Invisible;
+
+    for room in cx.rooms().rooms_of(handle) {
+        let frame = match (was_invisible, invisible) {
+            (false, true) => ServerFrame::Logout {
+                room: room.clone(),
+                handle: handle.to_string(),
+                reason: LogoutReason::Left,
+            },
+            (true, false) => ServerFrame::Login {
+                room: room.clone(),
+                handle: handle.to_string(),
+            },
+            _ => break,
+        };
+        cx.broadcast_to_room_except(&room, handle, frame);
+    }
+
+    let frame = status_changed(handle, status.clone());
+    if !invisible && old != status {
+        cx.broadcast_to_peers(handle, frame.clone());
+    }
+    cx.users().send(handle, frame);
+}
+
+fn status_changed(handle: &str, status: Status) -> ServerFrame {
+    ServerFrame::StatusChanged {
+        handle: handle.to_string(),
+        presence: status.presence,
+        status: status.text,
+    }
+}
+
+/// Adds the user to the room, lets the other members know and sends the user a list of members
+/// already present and their statuses, followed by recent history and read markers.
+async fn join_room<F>(cx: &Context, user: &UserGuard<'_, F>, room: &str)
+where
+    F: Fn(&str, &UserPool),
+{
+    cx.rooms().join(room, user.handle());
+
+    let status = cx.users().status(user.handle()).unwrap_or_default();
+    if status.presence != Presence::Invisible {
+        cx.broadcast_to_room_except(
+            room,
+            user.handle(),
+            ServerFrame::Login {
+                room: room.to_string(),
+                handle: user.handle().to_string(),
+            },
+        );
+        if status != Status::default() {
+            cx.broadcast_to_room_except(room, user.handle(), status_changed(user.handle(), status));
+        }
+    }
+
+    let mut statuses = Vec::new();
+    for member in cx.rooms().members(room) {
+        let status = cx.users().status(&member).unwrap_or_default();
+        if status.presence == Presence::Invisible && member != user.handle() {
+            continue;
+        }
+        if status != Status::default() {
+            statuses.push(status_changed(&member, status));
+        }
+        let _ = user.send(ServerFrame::Present {
+            room: room.to_string(),
+            handle: member,
+        });
+    }
+    for frame in statuses {
+        let _ = user.send(frame);
+    }
+
+    for msg in cx.store().last(room, cx.config().history_replay).await {
+        let reactions = reactions_of(&msg);
+        let _ = user.send(msg.into());
+        if let Some(frame) = reactions {
+            let _ = user.send(frame);
+        }
+    }
+
+    for (handle, msg_id) in cx.read_markers().markers(room) {
+        let _ = user.send(ServerFrame::ReadUpTo {
+            room: room.to_string(),
+            handle,
+            msg_id,
+        });
+    }
+}
+
+/// The [`ServerFrame::Reactions`] to send along with a message from history, if it has any.
+fn reactions_of(msg: &StoredMsg) -> Option<ServerFrame> {
+    (!msg.reactions.is_empty()).then(|| ServerFrame::Reactions {
+        room: msg.room.clone(),
+        msg_id: msg.id,
+        reactions: msg.reactions.counts(),
+    })
+}
+
+/// Looks up a message the user wants to change, making sure they sent it.
+async fn own_msg(
+    cx: &Context,
+    id: RequestId,
+    msg_id: MsgId,
+    handle: &str,
+) -> Result<StoredMsg, ServerFrame> {
+    match cx.store().get(msg_id).await {
+        Some(msg) if msg.sender == handle => Ok(msg),
+        Some(_) => Err(ServerFrame::error(
+            id,
+            ErrorCode::Forbidden,
+            "not your message",
+        )),
+        None => Err(unknown_msg(id)),
+    }
+}
+
+fn unknown_msg(id: RequestId) -> ServerFrame {
+    ServerFrame::error(id, ErrorCode::UnknownMsg, "unknown message")
+}
+
+/// The error to respond with when a user refers to a room they're not in.
+fn not_in_room(cx: &Context, id: RequestId, room: &str) -> ServerFrame {
+    if cx.rooms().exists(room) {
+        ServerFrame::error(id, ErrorCode::NotInRoom, "not in room")
+    } else {
+        ServerFrame::error(id, ErrorCode::UnknownRoom, "unknown room")
+    }
+}
+
+fn invalid_handle(cx: &Context, id: RequestId) -> ServerFrame {
+    ServerFrame::error(
+        id,
+        ErrorCode::InvalidHandle,
+        format!(
+            "handles must be 1 to {} characters long, without whitespace",
+            cx.config().max_handle_len
+        ),
+    )
+}
+
+fn is_valid_handle(handle: &str, max_len: usize) -> bool {
+    !handle.is_empty()
+        && handle.chars().count() <= max_len
+        && !handle.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+fn invalid_room(cx: &Context, id: RequestId) -> ServerFrame {
+    ServerFrame::error(
+        id,
+        ErrorCode::InvalidRoom,
+        format!(
+            "room names must be 1 to {} characters long, without whitespace",
+            cx.config().max_room_len
+        ),
+    )
+}
+
+fn is_valid_room(room: &str, max_len: usize) -> bool {
+    !room.is_empty()
+        && room.chars().count() <= max_len
+        && !room.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+/// Reactions are meant to be a single emoji, but there's no telling what counts as one, so this
+/// only keeps out the obviously wrong.
+fn is_valid_reaction(emoji: &str, max_len: usize) -> bool {
+    !emoji.is_empty()
+        && emoji.len() <= max_len
+        && !emoji.chars().any(|c| c.is_whitespace() || c.is_control())
+}
 
-    None
+fn utc_now() -> Timestamp {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as Timestamp)
+        .unwrap_or_default()
 }
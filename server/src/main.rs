@@ -1,12 +1,69 @@
+use minichat_server::config::Config;
+use minichat_server::protocol::tcp::tcp_sink_stream;
 use minichat_server::protocol::ws::ws_sink_stream;
+use minichat_server::{Server, Shutdown};
 
+/// Every argument is an address to listen on, see [`listen`].
 #[tokio::main]
 async fn main() -> Result<(), std::io::Error> {
-    let addr = std::env::args()
-        .nth(1)
-        .unwrap_or_else(|| "127.0.0.1:3333".to_string());
+    let mut addrs: Vec<_> = std::env::args().skip(1).collect();
+    if addrs.is_empty() {
+        addrs.push("127.0.0.1:3333".to_string());
+    }
 
-    minichat_server::serve_tcp(&addr, ws_sink_stream).await?;
+    let config = Config {
+        history_file: std::env::var_os("MC_HISTORY_FILE").map(Into::into),
+        tls_cert_file: std::env::var_os("MC_TLS_CERT").map(Into::into),
+        tls_key_file: std::env::var_os("MC_TLS_KEY").map(Into::into),
+        ..Config::default()
+    };
+
+    let server = addrs
+        .iter()
+        .fold(Server::new(config)?, |server, addr| listen(server, addr));
+    server.serve_until(shutdown_signal()).await?;
 
     Ok(())
 }
+
+/// Listens on a TCP address with WebSocket, unless it's prefixed with `wss://` for WebSocket over
+/// TLS or `tcp://` for length-prefixed frames on raw TCP. Unix domain socket paths are prefixed
+/// with `unix:` for length-prefixed frames, or `ws+unix:` for WebSocket.
+fn listen(server: Server, addr: &str) -> Server {
+    #[cfg(unix)]
+    if let Some(path) = addr.strip_prefix("unix:") {
+        return server.listen_unix(path, tcp_sink_stream);
+    } else if let Some(path) = addr.strip_prefix("ws+unix:") {
+        return server.listen_unix(path, ws_sink_stream);
+    }
+
+    if let Some(addr) = addr.strip_prefix("wss://") {
+        return server.listen_tls(addr, ws_sink_stream);
+    }
+
+    match addr.strip_prefix("tcp://") {
+        Some(addr) => server.listen_tcp(addr, tcp_sink_stream),
+        None => server.listen_tcp(addr.strip_prefix("ws://").unwrap_or(addr), ws_sink_stream),
+    }
+}
+
+/// Resolves on SIGINT, or SIGTERM where there is such a thing.
+async fn shutdown_signal() -> Shutdown {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+
+    Shutdown {
+        reason: "server is shutting down".to_string(),
+        reconnect_after: None,
+    }
+}
@@ -0,0 +1,80 @@
+//! Length-prefixed frames, for raw TCP and other plain byte streams like Unix domain sockets.
+//! Every frame is sent as its length, a little-endian `u32`, followed by the frame itself.
+
+use std::io;
+
+use futures_util::{sink, stream, Sink, Stream};
+use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
+
+use crate::codec::Codec;
+use crate::frame::{ClientFrame, DecodeError, EncodeError, ServerFrame};
+use crate::stream::{wrap_client_sink, wrap_client_stream, TransportMsg};
+
+/// The longest frame clients can send. Longer ones are skipped and reported as errors.
+pub const MAX_FRAME_LEN: u32 = 1 << 20;
+
+/// The bytes of a single frame, without the length prefix.
+pub struct TcpMsg(Vec<u8>);
+
+impl TransportMsg for TcpMsg {
+    fn into_client_frame(self, codec: &Codec) -> Result<Option<ClientFrame>, DecodeError> {
+        codec.decode_client_frame(&self.0).map(Some)
+    }
+
+    fn from_server_frame(frame: ServerFrame, codec: &Codec) -> Result<Option<Self>, EncodeError> {
+        let Some(bytes) = codec.encode_server_frame(frame)? else {
+            return Ok(None);
+        };
+        u32::try_from(bytes.len()).map_err(|_| EncodeError)?;
+        Ok(Some(TcpMsg(bytes)))
+    }
+}
+
+pub async fn tcp_sink_stream<S>(
+    stream: S,
+) -> Result<
+    (
+        impl Sink<ServerFrame, Error = ()>,
+        impl Stream<Item = Result<Option<ClientFrame>, DecodeError>>,
+    ),
+    String,
+>
+where
+    S: AsyncRead + AsyncWrite + 'static,
+{
+    let (reader, writer) = tokio::io::split(stream);
+    // the stream ends with the connection, or with the first I/O error since there's no telling
+    // where the next frame starts after that
+    let stream = stream::unfold(reader, |mut reader| async move {
+        read_msg(&mut reader).await.ok().map(|msg| (msg, reader))
+    });
+    let sink = sink::unfold(writer, |mut writer, msg: TcpMsg| async move {
+        let mut bytes = Vec::with_capacity(4 + msg.0.len());
+        bytes.extend_from_slice(&(msg.0.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&msg.0);
+        writer.write_all(&bytes).await?;
+        Ok::<_, io::Error>(writer)
+    });
+    let codec = Codec::new();
+
+    Ok((
+        wrap_client_sink(Box::pin(sink), codec.clone()),
+        wrap_client_stream(Box::pin(stream), codec),
+    ))
+}
+
+async fn read_msg<R>(reader: &mut R) -> io::Result<Result<TcpMsg, DecodeError>>
+where
+    R: AsyncRead + Unpin,
+{
+    let len = reader.read_u32_le().await?;
+    if len > MAX_FRAME_LEN {
+        // skip it, so the next frame can still be read
+        tokio::io::copy(&mut (&mut *reader).take(len.into()), &mut tokio::io::sink()).await?;
+        return Ok(Err(DecodeError::FrameTooLong));
+    }
+
+    let mut bytes = vec![0; len as usize];
+    reader.read_exact(&mut bytes).await?;
+    Ok(Ok(TcpMsg(bytes)))
+}
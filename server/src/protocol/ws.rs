@@ -1,48 +1,54 @@
-use borsh::{BorshDeserialize as _, BorshSerialize as _};
-use futures_util::{Sink, Stream, StreamExt as _};
-use tokio::net::TcpStream;
-use tungstenite::Message as WsMessage;
+use std::future::{self, Future as _};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
 
-use crate::frame::{ClientFrame, DecodeError, ServerFrame};
-use crate::stream::{wrap_client_sink, wrap_client_stream};
+use futures_channel::mpsc::{self, SendError};
+use futures_util::{Sink, SinkExt as _, Stream, StreamExt as _};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::CloseFrame;
+use tungstenite::Message as WsMessage;
 
-impl TryFrom<WsMessage> for ClientFrame {
-    type Error = DecodeError;
+use crate::codec::{Codec, Format};
+use crate::frame::{ClientFrame, DecodeError, EncodeError, ServerFrame};
+use crate::stream::{wrap_client_sink, wrap_client_stream, TransportMsg};
 
-    fn try_from(msg: WsMessage) -> Result<Self, Self::Error> {
-        if let WsMessage::Binary(bytes) = msg {
-            Self::try_from_slice(&bytes).map_err(|_| DecodeError::InvalidFrame)
-        } else {
-            Err(DecodeError::InvalidWebsocketFrame)
+impl TransportMsg for WsMessage {
+    fn into_client_frame(self, codec: &Codec) -> Result<Option<ClientFrame>, DecodeError> {
+        match self {
+            WsMessage::Binary(bytes) => codec.decode_client_frame(&bytes).map(Some),
+            WsMessage::Text(text) => codec.decode_client_frame_json(&text).map(Some),
+            WsMessage::Pong(_) => Ok(None),
+            _ => Err(DecodeError::InvalidWebsocketFrame),
         }
     }
-}
 
-impl TryFrom<WsMessage> for ServerFrame {
-    type Error = DecodeError;
-
-    fn try_from(msg: WsMessage) -> Result<Self, Self::Error> {
-        if let WsMessage::Binary(bytes) = msg {
-            Self::try_from_slice(&bytes).map_err(|_| DecodeError::InvalidFrame)
-        } else {
-            Err(DecodeError::InvalidWebsocketFrame)
+    /// Clients that send text frames get text frames back.
+    fn from_server_frame(frame: ServerFrame, codec: &Codec) -> Result<Option<Self>, EncodeError> {
+        match codec.format() {
+            Some(Format::Json) => Ok(codec.encode_server_frame_json(frame)?.map(WsMessage::Text)),
+            _ => Ok(codec.encode_server_frame(frame)?.map(WsMessage::Binary)),
         }
     }
-}
-
-impl TryFrom<ClientFrame> for WsMessage {
-    type Error = ();
-
-    fn try_from(frame: ClientFrame) -> Result<Self, Self::Error> {
-        Ok(WsMessage::Binary(frame.try_to_vec().map_err(|_| ())?))
-    }
-}
 
-impl TryFrom<ServerFrame> for WsMessage {
-    type Error = ();
-
-    fn try_from(frame: ServerFrame) -> Result<Self, Self::Error> {
-        Ok(WsMessage::Binary(frame.try_to_vec().map_err(|_| ())?))
+    fn closing(frame: &ServerFrame) -> Option<Self> {
+        let (code, reason) = match frame {
+            ServerFrame::Close { msg, .. } => (CloseCode::Policy, msg),
+            ServerFrame::ServerShutdown { reason, .. } => (CloseCode::Away, reason),
+            _ => return None,
+        };
+        // close frames only have room for this much of a reason
+        let mut len = reason.len().min(123);
+        while !reason.is_char_boundary(len) {
+            len -= 1;
+        }
+        Some(WsMessage::Close(Some(CloseFrame {
+            code,
+            reason: reason[..len].to_string().into(),
+        })))
     }
 }
 
@@ -52,19 +58,106 @@ impl From<tungstenite::Error> for DecodeError {
     }
 }
 
-pub async fn ws_sink_stream(
-    tcp_stream: TcpStream,
+/// How often clients are pinged. Their pongs keep the connection alive, even if they don't send
+/// [`ClientFrameType::Ping`](crate::frame::ClientFrameType::Ping)s of their own.
+pub const PING_INTERVAL: Duration = Duration::from_secs(20);
+
+pub async fn ws_sink_stream<S>(
+    stream: S,
 ) -> Result<
     (
         impl Sink<ServerFrame, Error = ()>,
-        impl Stream<Item = Result<ClientFrame, DecodeError>>,
+        impl Stream<Item = Result<Option<ClientFrame>, DecodeError>>,
     ),
     String,
-> {
-    let ws_stream = tokio_tungstenite::accept_async(tcp_stream)
+>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    ws_sink_stream_with_ping_interval(stream, PING_INTERVAL).await
+}
+
+/// Like [`ws_sink_stream`], but pings clients every `ping_interval` instead.
+pub async fn ws_sink_stream_with_ping_interval<S>(
+    stream: S,
+    ping_interval: Duration,
+) -> Result<
+    (
+        impl Sink<ServerFrame, Error = ()>,
+        impl Stream<Item = Result<Option<ClientFrame>, DecodeError>>,
+    ),
+    String,
+>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let ws_stream = tokio_tungstenite::accept_async(stream)
         .await
         .map_err(|e| format!("Error during the websocket handshake occurred: {}", e))?;
     let (sink, stream) = ws_stream.split();
+    // tungstenite answers pings and close frames on its own, they aren't frames of ours
+    let stream = stream
+        .filter(|msg| future::ready(!matches!(msg, Ok(WsMessage::Ping(_) | WsMessage::Close(_)))));
+    let (tx, rx) = mpsc::channel(0);
+    let sink = WsSink {
+        tx,
+        writer: tokio::spawn(write(sink, rx, ping_interval)),
+    };
+    let codec = Codec::new();
+
+    Ok((
+        wrap_client_sink(sink, codec.clone()),
+        wrap_client_stream(stream, codec),
+    ))
+}
+
+/// Hands messages to the task that writes them to the WebSocket, see [`write`].
+struct WsSink {
+    tx: mpsc::Sender<WsMessage>,
+    writer: JoinHandle<()>,
+}
 
-    Ok((wrap_client_sink(sink), wrap_client_stream(stream)))
+impl Sink<WsMessage> for WsSink {
+    type Error = SendError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), SendError>> {
+        self.tx.poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, msg: WsMessage) -> Result<(), SendError> {
+        self.tx.start_send(msg)
+    }
+
+    // the writer takes messages one at a time, so there's nothing to flush on this end
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), SendError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    /// Waits until everything sent so far has been written and the WebSocket is closed.
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), SendError>> {
+        self.tx.close_channel();
+        Pin::new(&mut self.writer).poll(cx).map(|_| Ok(()))
+    }
+}
+
+/// Writes messages to the WebSocket, and pings the client every `ping_interval`, until the
+/// sending side goes away.
+async fn write<S>(mut sink: S, mut rx: mpsc::Receiver<WsMessage>, ping_interval: Duration)
+where
+    S: Sink<WsMessage> + Unpin,
+{
+    let mut pings = tokio::time::interval_at(Instant::now() + ping_interval, ping_interval);
+    loop {
+        let msg = tokio::select! {
+            msg = rx.next() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+            _ = pings.tick() => WsMessage::Ping(Vec::new()),
+        };
+        if sink.send(msg).await.is_err() {
+            return;
+        }
+    }
+    let _ = sink.close().await;
 }
@@ -0,0 +1,363 @@
+use std::fmt;
+use std::io::{Error as IoError, ErrorKind};
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::future::{self, BoxFuture};
+use futures_util::{pin_mut, stream, Future, FutureExt as _, Sink, Stream, StreamExt as _};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc;
+use tokio_rustls::server::TlsStream;
+
+use crate::config::Config;
+use crate::context::Context;
+use crate::frame::{ClientFrame, DecodeError, ServerFrame};
+use crate::logic::handle_connection;
+use crate::tls::TlsCerts;
+
+/// Why and for how long the server is going away, for
+/// [`ServerFrame::ServerShutdown`](crate::frame::ServerFrame::ServerShutdown).
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    pub reason: String,
+    /// When clients should try to reconnect, if the server expects to be back.
+    pub reconnect_after: Option<Duration>,
+}
+
+/// Who's on the other end of a connection.
+#[derive(Debug, Clone)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    /// A client connected to a Unix domain socket, known by its process ID if the OS tells.
+    #[cfg(unix)]
+    Unix {
+        pid: Option<i32>,
+    },
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Tcp(addr) => addr.fmt(f),
+            #[cfg(unix)]
+            Peer::Unix { pid: Some(pid) } => write!(f, "unix socket client (pid {})", pid),
+            #[cfg(unix)]
+            Peer::Unix { pid: None } => write!(f, "unix socket client"),
+        }
+    }
+}
+
+/// Every open connection holds a clone of this, so shutting down can tell when they're all gone.
+type Tracker = mpsc::Sender<()>;
+
+type Listener = Box<dyn FnOnce(Context, Tracker) -> BoxFuture<'static, Result<(), IoError>> + Send>;
+
+/// A mini-chat server listening on any number of addresses, possibly with different transports.
+/// Users see each other no matter how they're connected.
+///
+/// ```no_run
+/// # use minichat_server::{config::Config, Server};
+/// # use minichat_server::protocol::{tcp::tcp_sink_stream, ws::ws_sink_stream};
+/// # async fn run() -> std::io::Result<()> {
+/// Server::new(Config::default())?
+///     .listen_tcp("127.0.0.1:3333", ws_sink_stream)
+///     .listen_tcp("127.0.0.1:3334", tcp_sink_stream)
+///     .serve()
+///     .await
+/// # }
+/// ```
+pub struct Server {
+    ctx: Context,
+    listeners: Vec<Listener>,
+}
+
+impl Server {
+    pub fn new(config: Config) -> Result<Self, IoError> {
+        Ok(Self {
+            ctx: Context::from_config(config)?,
+            listeners: Vec::new(),
+        })
+    }
+
+    /// Accepts TCP connections on `addr`. The `stream_builder` callable is meant to split the
+    /// [`TcpStream`] and decorate both the stream and sink. It implements the transport for
+    /// mini-chat frames, e.g. WebSocket with [`ws_sink_stream`](crate::protocol::ws::ws_sink_stream)
+    /// or length-prefixed frames with [`tcp_sink_stream`](crate::protocol::tcp::tcp_sink_stream).
+    pub fn listen_tcp<F, FUT, SNK, STR>(
+        mut self,
+        addr: impl Into<String>,
+        stream_builder: F,
+    ) -> Self
+    where
+        F: Fn(TcpStream) -> FUT + Send + 'static,
+        FUT: Future<Output = Result<(SNK, STR), String>> + Send + 'static,
+        STR: Stream<Item = Result<Option<ClientFrame>, DecodeError>> + Send + Unpin + 'static,
+        SNK: Sink<ServerFrame, Error = ()> + Send + Unpin + 'static,
+    {
+        let addr = addr.into();
+        self.listeners.push(Box::new(move |ctx, tracker| {
+            accept_tcp(addr, stream_builder, ctx, tracker).boxed()
+        }));
+        self
+    }
+
+    /// Accepts TLS connections on `addr`, like [`Server::listen_tcp`] does plain ones. The
+    /// certificate and key are read from [`Config::tls_cert_file`] and [`Config::tls_key_file`],
+    /// and read again whenever the process receives SIGHUP.
+    pub fn listen_tls<F, FUT, SNK, STR>(
+        mut self,
+        addr: impl Into<String>,
+        stream_builder: F,
+    ) -> Self
+    where
+        F: Fn(TlsStream<TcpStream>) -> FUT + Send + Sync + 'static,
+        FUT: Future<Output = Result<(SNK, STR), String>> + Send + 'static,
+        STR: Stream<Item = Result<Option<ClientFrame>, DecodeError>> + Send + Unpin + 'static,
+        SNK: Sink<ServerFrame, Error = ()> + Send + Unpin + 'static,
+    {
+        let addr = addr.into();
+        self.listeners.push(Box::new(move |ctx, tracker| {
+            accept_tls(addr, stream_builder, ctx, tracker).boxed()
+        }));
+        self
+    }
+
+    /// Accepts connections on a Unix domain socket at `path`, like [`Server::listen_tcp`] does on
+    /// TCP. A socket left behind at `path` by an earlier run is replaced, but not one another
+    /// server is still listening on. The socket is removed again once the server stops listening.
+    #[cfg(unix)]
+    pub fn listen_unix<F, FUT, SNK, STR>(
+        mut self,
+        path: impl Into<PathBuf>,
+        stream_builder: F,
+    ) -> Self
+    where
+        F: Fn(UnixStream) -> FUT + Send + 'static,
+        FUT: Future<Output = Result<(SNK, STR), String>> + Send + 'static,
+        STR: Stream<Item = Result<Option<ClientFrame>, DecodeError>> + Send + Unpin + 'static,
+        SNK: Sink<ServerFrame, Error = ()> + Send + Unpin + 'static,
+    {
+        let path = path.into();
+        self.listeners.push(Box::new(move |ctx, tracker| {
+            accept_unix(path, stream_builder, ctx, tracker).boxed()
+        }));
+        self
+    }
+
+    /// Serves until every listener stops accepting connections.
+    pub async fn serve(self) -> Result<(), IoError> {
+        self.serve_until(future::pending()).await
+    }
+
+    /// Like [`Server::serve`], but shuts down once `shutdown` resolves. New connections are no
+    /// longer accepted, everyone connected is told about the shutdown and disconnected, and
+    /// connections get up to [`Config::drain_timeout`] to wind down before they're left behind.
+    pub async fn serve_until(
+        self,
+        shutdown: impl Future<Output = Shutdown>,
+    ) -> Result<(), IoError> {
+        let (tracker, mut drained) = mpsc::channel(1);
+        let shutdown = {
+            let listeners = self
+                .listeners
+                .into_iter()
+                .map(|listener| listener(self.ctx.clone(), tracker.clone()));
+            let listeners = future::try_join_all(listeners);
+            drop(tracker);
+
+            pin_mut!(listeners, shutdown);
+            tokio::select! {
+                res = listeners => {
+                    // whoever is still connected keeps being served
+                    return res.map(|_| ());
+                }
+                shutdown = shutdown => shutdown,
+            }
+            // the listeners are dropped here, so no more connections are accepted
+        };
+
+        println!("Shutting down: {}", shutdown.reason);
+        self.ctx.shut_down(shutdown);
+        // nothing is ever sent, this only returns once every connection dropped its tracker
+        let drained = drained.recv();
+        if tokio::time::timeout(self.ctx.config().drain_timeout, drained)
+            .await
+            .is_err()
+        {
+            println!("Some connections didn't close in time");
+        }
+
+        Ok(())
+    }
+}
+
+async fn accept_tcp<F, FUT, SNK, STR>(
+    addr: String,
+    stream_builder: F,
+    ctx: Context,
+    tracker: Tracker,
+) -> Result<(), IoError>
+where
+    F: Fn(TcpStream) -> FUT,
+    FUT: Future<Output = Result<(SNK, STR), String>> + Send + 'static,
+    STR: Stream<Item = Result<Option<ClientFrame>, DecodeError>> + Send + Unpin + 'static,
+    SNK: Sink<ServerFrame, Error = ()> + Send + Unpin + 'static,
+{
+    let listener = TcpListener::bind(&addr).await?;
+    println!("Listening on: {}", addr);
+
+    while let Ok((tcp_stream, addr)) = listener.accept().await {
+        // the handshake happens on the connection's own task, so slow clients can't hold up others
+        let handshake = stream_builder(tcp_stream);
+        let peer = Peer::Tcp(addr);
+        tokio::spawn(connect(ctx.clone(), handshake, peer, tracker.clone()));
+    }
+
+    Ok(())
+}
+
+async fn accept_tls<F, FUT, SNK, STR>(
+    addr: String,
+    stream_builder: F,
+    ctx: Context,
+    tracker: Tracker,
+) -> Result<(), IoError>
+where
+    F: Fn(TlsStream<TcpStream>) -> FUT + Send + Sync + 'static,
+    FUT: Future<Output = Result<(SNK, STR), String>> + Send + 'static,
+    STR: Stream<Item = Result<Option<ClientFrame>, DecodeError>> + Send + Unpin + 'static,
+    SNK: Sink<ServerFrame, Error = ()> + Send + Unpin + 'static,
+{
+    let (Some(cert_file), Some(key_file)) =
+        (&ctx.config().tls_cert_file, &ctx.config().tls_key_file)
+    else {
+        return Err(IoError::new(
+            ErrorKind::InvalidInput,
+            "listening with TLS needs a certificate and a key",
+        ));
+    };
+    let certs = Arc::new(TlsCerts::load(cert_file, key_file)?);
+    let acceptor = certs.clone().acceptor();
+    let stream_builder = Arc::new(stream_builder);
+
+    let mut hangups = hangups()?;
+    let listener = TcpListener::bind(&addr).await?;
+    println!("Listening on: {} (TLS)", addr);
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let Ok((tcp_stream, addr)) = accepted else {
+                    break;
+                };
+                // TLS is part of the handshake, so it's done on the connection's task and under
+                // the same deadline
+                let acceptor = acceptor.clone();
+                let stream_builder = stream_builder.clone();
+                let handshake = async move {
+                    let tls_stream = acceptor
+                        .accept(tcp_stream)
+                        .await
+                        .map_err(|e| format!("Error during the TLS handshake occurred: {}", e))?;
+                    stream_builder(tls_stream).await
+                };
+                let peer = Peer::Tcp(addr);
+                tokio::spawn(connect(ctx.clone(), handshake, peer, tracker.clone()));
+            }
+            Some(()) = hangups.next() => match certs.reload() {
+                Ok(()) => println!("Reloaded TLS certificate {}", certs.cert_file().display()),
+                Err(e) => println!("Failed to reload TLS certificate, keeping the old one: {}", e),
+            },
+        }
+    }
+
+    Ok(())
+}
+
+/// Yields whenever the process receives SIGHUP, where there is such a thing.
+fn hangups() -> Result<impl Stream<Item = ()> + Unpin, IoError> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let hangup = signal(SignalKind::hangup())?;
+        Ok(stream::unfold(hangup, |mut hangup| async move {
+            hangup.recv().await.map(|()| ((), hangup))
+        })
+        .boxed())
+    }
+    #[cfg(not(unix))]
+    Ok(stream::pending())
+}
+
+#[cfg(unix)]
+async fn accept_unix<F, FUT, SNK, STR>(
+    path: PathBuf,
+    stream_builder: F,
+    ctx: Context,
+    tracker: Tracker,
+) -> Result<(), IoError>
+where
+    F: Fn(UnixStream) -> FUT,
+    FUT: Future<Output = Result<(SNK, STR), String>> + Send + 'static,
+    STR: Stream<Item = Result<Option<ClientFrame>, DecodeError>> + Send + Unpin + 'static,
+    SNK: Sink<ServerFrame, Error = ()> + Send + Unpin + 'static,
+{
+    use std::os::unix::fs::FileTypeExt as _;
+
+    // binding to a socket that's left behind would fail, but it's only stale if nothing is
+    // listening on it anymore
+    let socket = std::fs::symlink_metadata(&path).is_ok_and(|meta| meta.file_type().is_socket());
+    if socket {
+        match UnixStream::connect(&path).await {
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => std::fs::remove_file(&path)?,
+            Err(e) => return Err(e),
+            Ok(_) => {
+                let msg = format!("{} is in use by another server", path.display());
+                return Err(IoError::new(ErrorKind::AddrInUse, msg));
+            }
+        }
+    }
+    let listener = UnixListener::bind(&path)?;
+    let _socket = RemoveOnDrop(path.clone());
+    println!("Listening on: {}", path.display());
+
+    while let Ok((unix_stream, _)) = listener.accept().await {
+        let pid = unix_stream.peer_cred().ok().and_then(|cred| cred.pid());
+        let handshake = stream_builder(unix_stream);
+        let peer = Peer::Unix { pid };
+        tokio::spawn(connect(ctx.clone(), handshake, peer, tracker.clone()));
+    }
+
+    Ok(())
+}
+
+/// Removes the file at the path once dropped, e.g. when a listener shuts down.
+#[cfg(unix)]
+struct RemoveOnDrop(PathBuf);
+
+#[cfg(unix)]
+impl Drop for RemoveOnDrop {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+async fn connect<FUT, SNK, STR>(ctx: Context, handshake: FUT, peer: Peer, _tracker: Tracker)
+where
+    FUT: Future<Output = Result<(SNK, STR), String>>,
+    STR: Stream<Item = Result<Option<ClientFrame>, DecodeError>> + Unpin,
+    SNK: Sink<ServerFrame, Error = ()> + Unpin,
+{
+    let timeout = ctx.config().handshake_timeout;
+    match tokio::time::timeout(timeout, handshake).await {
+        Ok(Ok((sink, stream))) => handle_connection(ctx, sink, stream, peer).await,
+        Ok(Err(e)) => println!("{}: {}", peer, e),
+        Err(_) => println!("{} didn't complete the handshake in time", peer),
+    }
+}
@@ -0,0 +1,1031 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet, VecDeque};
+use std::fs::OpenOptions;
+use std::io::{BufReader, Error as IoError, ErrorKind, Read as _, Seek as _, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use borsh::{BorshDeserialize, BorshSerialize};
+use tokio::io::AsyncWriteExt as _;
+
+use crate::frame::{HistoryMsg, MsgId, Reaction, ServerFrame, Timestamp};
+
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct StoredMsg {
+    pub id: MsgId,
+    pub timestamp: Timestamp,
+    pub room: String,
+    pub sender: String,
+    pub msg: String,
+    pub reply_to: Option<MsgId>,
+    pub mentions: Vec<String>,
+    // logged separately, see `LogEntry::React`
+    #[borsh_skip]
+    pub reactions: Reactions,
+}
+
+impl From<StoredMsg> for ServerFrame {
+    fn from(msg: StoredMsg) -> Self {
+        ServerFrame::Broadcast {
+            msg_id: msg.id,
+            timestamp: msg.timestamp,
+            room: msg.room,
+            sender: msg.sender,
+            msg: msg.msg,
+            reply_to: msg.reply_to,
+            mentions: msg.mentions,
+        }
+    }
+}
+
+/// Who reacted to a message with what.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Reactions(BTreeMap<String, BTreeSet<String>>);
+
+impl Reactions {
+    pub fn set(&mut self, emoji: String, handle: String, on: bool) {
+        if on {
+            self.0.entry(emoji).or_default().insert(handle);
+        } else if let Some(handles) = self.0.get_mut(&emoji) {
+            handles.remove(&handle);
+            if handles.is_empty() {
+                self.0.remove(&emoji);
+            }
+        }
+    }
+
+    /// Moves the reactions of `old` over to `new`.
+    pub fn rename(&mut self, old: &str, new: &str) {
+        for handles in self.0.values_mut() {
+            if handles.remove(old) {
+                handles.insert(new.to_string());
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn counts(&self) -> Vec<Reaction> {
+        self.0
+            .iter()
+            .map(|(emoji, handles)| Reaction {
+                emoji: emoji.clone(),
+                count: handles.len() as u32,
+            })
+            .collect()
+    }
+}
+
+impl From<StoredMsg> for HistoryMsg {
+    fn from(msg: StoredMsg) -> Self {
+        HistoryMsg {
+            msg_id: msg.id,
+            timestamp: msg.timestamp,
+            sender: msg.sender,
+            msg: msg.msg,
+            reply_to: msg.reply_to,
+        }
+    }
+}
+
+/// Message history. Ids are assigned by the caller, but messages might not be pushed in the
+/// exact order of their ids, so implementations need to take care of ordering.
+#[async_trait]
+pub trait MessageStore: std::fmt::Debug + Send + Sync {
+    async fn push(&self, msg: StoredMsg) -> Result<(), IoError>;
+
+    /// Up to `limit` messages posted to the room with ids lower than `before`, oldest first.
+    /// The most recent messages are returned if `before` is `None`.
+    async fn history(&self, room: &str, before: Option<MsgId>, limit: usize) -> Vec<StoredMsg>;
+
+    /// The last `limit` messages posted to the room, oldest first.
+    async fn last(&self, room: &str, limit: usize) -> Vec<StoredMsg> {
+        self.history(room, None, limit).await
+    }
+
+    /// Up to `limit` replies to the message with ids lower than `before`, oldest first.
+    async fn replies(&self, id: MsgId, before: Option<MsgId>, limit: usize) -> Vec<StoredMsg>;
+
+    /// The highest id stored so far, if any. Deleted messages count too, so that their ids
+    /// aren't handed out again.
+    fn last_id(&self) -> Option<MsgId>;
+
+    /// The message with the given id, unless it was deleted or dropped from the store.
+    async fn get(&self, id: MsgId) -> Option<StoredMsg>;
+
+    /// Replaces the text of a message, along with who it mentions. Returns `false` if there's
+    /// no such message.
+    async fn edit(&self, id: MsgId, msg: String, mentions: Vec<String>) -> Result<bool, IoError>;
+
+    /// Returns `false` if there's no such message.
+    async fn delete(&self, id: MsgId) -> Result<bool, IoError>;
+
+    /// Adds or removes a user's reaction to a message and returns all of the message's
+    /// reactions, or `None` if there's no such message.
+    async fn react(
+        &self,
+        id: MsgId,
+        emoji: String,
+        handle: String,
+        on: bool,
+    ) -> Result<Option<Reactions>, IoError>;
+
+    /// Moves the user's reactions over to their new handle.
+    async fn rename(&self, old: &str, new: &str) -> Result<(), IoError>;
+}
+
+/// Keeps the last `capacity` messages (across all rooms) in memory.
+#[derive(Debug)]
+pub struct MemoryStore {
+    capacity: usize,
+    msgs: Mutex<VecDeque<StoredMsg>>,
+    last_id: Mutex<Option<MsgId>>,
+}
+
+impl MemoryStore {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            msgs: Mutex::new(VecDeque::with_capacity(capacity)),
+            last_id: Mutex::new(None),
+        }
+    }
+}
+
+#[async_trait]
+impl MessageStore for MemoryStore {
+    async fn push(&self, msg: StoredMsg) -> Result<(), IoError> {
+        raise_last_id(&self.last_id, msg.id);
+        if self.capacity == 0 {
+            return Ok(());
+        }
+
+        let mut msgs = self.msgs.lock().unwrap();
+        if msgs.len() == self.capacity {
+            msgs.pop_front();
+        }
+        let ix = msgs.partition_point(|m| m.id < msg.id);
+        msgs.insert(ix, msg);
+
+        Ok(())
+    }
+
+    async fn history(&self, room: &str, before: Option<MsgId>, limit: usize) -> Vec<StoredMsg> {
+        let msgs = self.msgs.lock().unwrap();
+        let end = before.map_or(msgs.len(), |before| msgs.partition_point(|m| m.id < before));
+        last_matching(msgs.range(..end), |m| m.room == room, limit)
+    }
+
+    async fn replies(&self, id: MsgId, before: Option<MsgId>, limit: usize) -> Vec<StoredMsg> {
+        let msgs = self.msgs.lock().unwrap();
+        let end = before.map_or(msgs.len(), |before| msgs.partition_point(|m| m.id < before));
+        last_matching(msgs.range(..end), |m| m.reply_to == Some(id), limit)
+    }
+
+    fn last_id(&self) -> Option<MsgId> {
+        *self.last_id.lock().unwrap()
+    }
+
+    async fn get(&self, id: MsgId) -> Option<StoredMsg> {
+        let msgs = self.msgs.lock().unwrap();
+        let ix = msgs.binary_search_by_key(&id, |m| m.id).ok()?;
+        Some(msgs[ix].clone())
+    }
+
+    async fn edit(&self, id: MsgId, msg: String, mentions: Vec<String>) -> Result<bool, IoError> {
+        let mut msgs = self.msgs.lock().unwrap();
+        Ok(match msgs.binary_search_by_key(&id, |m| m.id) {
+            Ok(ix) => {
+                msgs[ix].msg = msg;
+                msgs[ix].mentions = mentions;
+                true
+            }
+            Err(_) => false,
+        })
+    }
+
+    async fn delete(&self, id: MsgId) -> Result<bool, IoError> {
+        let mut msgs = self.msgs.lock().unwrap();
+        Ok(match msgs.binary_search_by_key(&id, |m| m.id) {
+            Ok(ix) => msgs.remove(ix).is_some(),
+            Err(_) => false,
+        })
+    }
+
+    async fn react(
+        &self,
+        id: MsgId,
+        emoji: String,
+        handle: String,
+        on: bool,
+    ) -> Result<Option<Reactions>, IoError> {
+        let mut msgs = self.msgs.lock().unwrap();
+        Ok(match msgs.binary_search_by_key(&id, |m| m.id) {
+            Ok(ix) => {
+                msgs[ix].reactions.set(emoji, handle, on);
+                Some(msgs[ix].reactions.clone())
+            }
+            Err(_) => None,
+        })
+    }
+
+    async fn rename(&self, old: &str, new: &str) -> Result<(), IoError> {
+        for msg in self.msgs.lock().unwrap().iter_mut() {
+            msg.reactions.rename(old, new);
+        }
+        Ok(())
+    }
+}
+
+/// An append-only log on disk. Only the last `capacity` messages (across all rooms) are kept in
+/// memory, older ones are read back from the log when they're asked for.
+#[derive(Debug)]
+pub struct FileStore {
+    path: PathBuf,
+    file: tokio::sync::Mutex<tokio::fs::File>,
+    recent: Mutex<Recent>,
+    last_id: Mutex<Option<MsgId>>,
+}
+
+// Remember: this is what ends up on disk. Like frames, the layout can't change without breaking
+// existing logs. Every entry is preceded by its length, a little-endian `u32`.
+#[derive(BorshSerialize, BorshDeserialize)]
+#[repr(u8)]
+enum LogEntry {
+    Msg(StoredMsg) = 0,
+    Edit {
+        id: MsgId,
+        msg: String,
+        mentions: Vec<String>,
+    } = 1,
+    Delete {
+        id: MsgId,
+    } = 2,
+    React {
+        id: MsgId,
+        emoji: String,
+        handle: String,
+        on: bool,
+    } = 3,
+    /// Applies to reactions to all messages logged before it.
+    Rename {
+        old: String,
+        new: String,
+    } = 4,
+}
+
+/// The messages a [`FileStore`] keeps in memory: every message that wasn't deleted, from
+/// `on_disk_below` on.
+#[derive(Debug)]
+struct Recent {
+    capacity: usize,
+    msgs: VecDeque<StoredMsg>,
+    /// Messages with lower ids are only found in the log.
+    on_disk_below: MsgId,
+}
+
+impl Recent {
+    fn insert(&mut self, msg: StoredMsg) {
+        if msg.id < self.on_disk_below {
+            return;
+        }
+        let ix = self.msgs.partition_point(|m| m.id < msg.id);
+        self.msgs.insert(ix, msg);
+        if self.msgs.len() > self.capacity {
+            let oldest = self.msgs.pop_front().unwrap();
+            self.on_disk_below = oldest.id + 1;
+        }
+    }
+
+    fn get_mut(&mut self, id: MsgId) -> Option<&mut StoredMsg> {
+        let ix = self.msgs.binary_search_by_key(&id, |m| m.id).ok()?;
+        Some(&mut self.msgs[ix])
+    }
+
+    fn remove(&mut self, id: MsgId) {
+        if let Ok(ix) = self.msgs.binary_search_by_key(&id, |m| m.id) {
+            self.msgs.remove(ix);
+        }
+    }
+
+    fn rename(&mut self, old: &str, new: &str) {
+        for msg in &mut self.msgs {
+            msg.reactions.rename(old, new);
+        }
+    }
+
+    fn last_matching(
+        &self,
+        pred: impl Fn(&StoredMsg) -> bool,
+        before: Option<MsgId>,
+        limit: usize,
+    ) -> Vec<StoredMsg> {
+        let end = before.map_or(self.msgs.len(), |before| {
+            self.msgs.partition_point(|m| m.id < before)
+        });
+        last_matching(self.msgs.range(..end), pred, limit)
+    }
+}
+
+impl FileStore {
+    /// Opens the log, creating it if it doesn't exist, and keeps the last `capacity` messages in
+    /// memory.
+    ///
+    /// A torn entry at the end of the log (e.g. from a crash mid-write) is truncated. Entries
+    /// that can't be read anywhere else mean the log is corrupted, which is an error.
+    pub fn open(path: impl AsRef<Path>, capacity: usize) -> Result<Self, IoError> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+
+        let mut recent = Recent {
+            capacity,
+            msgs: VecDeque::with_capacity(capacity),
+            on_disk_below: 0,
+        };
+        let mut last_id = None;
+        // the message is logged before any changes to it, and changes to messages that are no
+        // longer in memory don't matter here
+        let valid_len = read_log(&mut file, |entry| match entry {
+            LogEntry::Msg(msg) => {
+                last_id = last_id.max(Some(msg.id));
+                recent.insert(msg);
+            }
+            LogEntry::Edit { id, msg, mentions } => {
+                if let Some(m) = recent.get_mut(id) {
+                    m.msg = msg;
+                    m.mentions = mentions;
+                }
+            }
+            LogEntry::Delete { id } => recent.remove(id),
+            LogEntry::React {
+                id,
+                emoji,
+                handle,
+                on,
+            } => {
+                if let Some(m) = recent.get_mut(id) {
+                    m.reactions.set(emoji, handle, on);
+                }
+            }
+            LogEntry::Rename { old, new } => recent.rename(&old, &new),
+        })
+        .map_err(|e| IoError::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        if valid_len < file.metadata()?.len() {
+            println!(
+                "{}: truncating a torn log entry at byte {}",
+                path.display(),
+                valid_len
+            );
+            file.set_len(valid_len)?;
+        }
+
+        Ok(Self {
+            path,
+            file: tokio::sync::Mutex::new(tokio::fs::File::from_std(file)),
+            recent: Mutex::new(recent),
+            last_id: Mutex::new(last_id),
+        })
+    }
+
+    async fn append(&self, entry: LogEntry) -> Result<(), IoError> {
+        let entry = entry.try_to_vec()?;
+        let len = u32::try_from(entry.len()).map_err(|_| IoError::from(ErrorKind::InvalidInput))?;
+        let mut bytes = Vec::with_capacity(4 + entry.len());
+        bytes.extend_from_slice(&len.to_le_bytes());
+        bytes.extend_from_slice(&entry);
+
+        let mut file = self.file.lock().await;
+        file.write_all(&bytes).await?;
+        file.flush().await
+    }
+
+    /// Adds up to `limit - msgs.len()` older messages matching `pred` from the log to the front
+    /// of `msgs`, if `msgs` doesn't have `limit` messages yet and there are any in the log only.
+    async fn fill_from_log<P>(
+        &self,
+        msgs: &mut Vec<StoredMsg>,
+        before: Option<MsgId>,
+        on_disk_below: MsgId,
+        limit: usize,
+        pred: P,
+    ) where
+        P: Fn(&StoredMsg) -> bool + Send + 'static,
+    {
+        if msgs.len() >= limit || on_disk_below == 0 {
+            return;
+        }
+        let before = before.map_or(on_disk_below, |before| before.min(on_disk_below));
+        let missing = limit - msgs.len();
+        match self.read_older(before, pred, missing).await {
+            Ok(mut older) => {
+                older.append(msgs);
+                *msgs = older;
+            }
+            Err(e) => println!("{}: {}", self.path.display(), e),
+        }
+    }
+
+    /// Reads up to `limit` messages matching `pred` with ids lower than `before` back from the
+    /// log, oldest first.
+    async fn read_older<P>(
+        &self,
+        before: MsgId,
+        pred: P,
+        limit: usize,
+    ) -> Result<Vec<StoredMsg>, IoError>
+    where
+        P: Fn(&StoredMsg) -> bool + Send + 'static,
+    {
+        let path = self.path.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut file = std::fs::File::open(path)?;
+            // deleted messages have to be known up front, or they'd take up room in `older`
+            let mut deleted = HashSet::new();
+            read_log(&mut file, |entry| {
+                if let LogEntry::Delete { id } = entry {
+                    deleted.insert(id);
+                }
+            })?;
+
+            let mut older = BTreeMap::new();
+            read_log(&mut file, |entry| match entry {
+                LogEntry::Msg(msg)
+                    if msg.id < before && !deleted.contains(&msg.id) && pred(&msg) =>
+                {
+                    older.insert(msg.id, msg);
+                    if older.len() > limit {
+                        older.pop_first();
+                    }
+                }
+                LogEntry::Edit { id, msg, mentions } => {
+                    if let Some(m) = older.get_mut(&id) {
+                        m.msg = msg;
+                        m.mentions = mentions;
+                    }
+                }
+                LogEntry::React {
+                    id,
+                    emoji,
+                    handle,
+                    on,
+                } => {
+                    if let Some(m) = older.get_mut(&id) {
+                        m.reactions.set(emoji, handle, on);
+                    }
+                }
+                LogEntry::Rename { old, new } => {
+                    for m in older.values_mut() {
+                        m.reactions.rename(&old, &new);
+                    }
+                }
+                _ => {}
+            })?;
+            Ok(older.into_values().collect())
+        })
+        .await?
+    }
+}
+
+/// Reads the log from the start, entry by entry, and returns how much of it was read. That's
+/// less than all of it if the last entry is torn, e.g. because it's still being written.
+fn read_log(file: &mut std::fs::File, mut f: impl FnMut(LogEntry)) -> Result<u64, IoError> {
+    file.seek(SeekFrom::Start(0))?;
+    let total_len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+
+    let mut valid_len = 0;
+    loop {
+        let rest = total_len - valid_len;
+        if rest < 4 {
+            return Ok(valid_len);
+        }
+        let mut len = [0; 4];
+        reader.read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len);
+        if u64::from(len) > rest - 4 {
+            return Ok(valid_len);
+        }
+
+        let mut bytes = vec![0; len as usize];
+        reader.read_exact(&mut bytes)?;
+        let entry = LogEntry::try_from_slice(&bytes).map_err(|_| {
+            let msg = format!("corrupted log entry at byte {}", valid_len);
+            IoError::new(ErrorKind::InvalidData, msg)
+        })?;
+        f(entry);
+        valid_len += 4 + u64::from(len);
+    }
+}
+
+#[async_trait]
+impl MessageStore for FileStore {
+    async fn push(&self, msg: StoredMsg) -> Result<(), IoError> {
+        self.append(LogEntry::Msg(msg.clone())).await?;
+
+        raise_last_id(&self.last_id, msg.id);
+        self.recent.lock().unwrap().insert(msg);
+
+        Ok(())
+    }
+
+    async fn history(&self, room: &str, before: Option<MsgId>, limit: usize) -> Vec<StoredMsg> {
+        let (mut msgs, on_disk_below) = {
+            let recent = self.recent.lock().unwrap();
+            let msgs = recent.last_matching(|m| m.room == room, before, limit);
+            (msgs, recent.on_disk_below)
+        };
+        let room = room.to_string();
+        self.fill_from_log(&mut msgs, before, on_disk_below, limit, move |m| {
+            m.room == room
+        })
+        .await;
+        msgs
+    }
+
+    async fn replies(&self, id: MsgId, before: Option<MsgId>, limit: usize) -> Vec<StoredMsg> {
+        let (mut msgs, on_disk_below) = {
+            let recent = self.recent.lock().unwrap();
+            let msgs = recent.last_matching(|m| m.reply_to == Some(id), before, limit);
+            (msgs, recent.on_disk_below)
+        };
+        self.fill_from_log(&mut msgs, before, on_disk_below, limit, move |m| {
+            m.reply_to == Some(id)
+        })
+        .await;
+        msgs
+    }
+
+    fn last_id(&self) -> Option<MsgId> {
+        *self.last_id.lock().unwrap()
+    }
+
+    async fn get(&self, id: MsgId) -> Option<StoredMsg> {
+        let on_disk_below = {
+            let mut recent = self.recent.lock().unwrap();
+            if let Some(msg) = recent.get_mut(id) {
+                return Some(msg.clone());
+            }
+            recent.on_disk_below
+        };
+        if id >= on_disk_below {
+            return None;
+        }
+        match self.read_older(on_disk_below, move |m| m.id == id, 1).await {
+            Ok(mut msgs) => msgs.pop(),
+            Err(e) => {
+                println!("{}: {}", self.path.display(), e);
+                None
+            }
+        }
+    }
+
+    async fn edit(&self, id: MsgId, msg: String, mentions: Vec<String>) -> Result<bool, IoError> {
+        if self.get(id).await.is_none() {
+            return Ok(false);
+        }
+        self.append(LogEntry::Edit {
+            id,
+            msg: msg.clone(),
+            mentions: mentions.clone(),
+        })
+        .await?;
+
+        if let Some(m) = self.recent.lock().unwrap().get_mut(id) {
+            m.msg = msg;
+            m.mentions = mentions;
+        }
+
+        Ok(true)
+    }
+
+    async fn delete(&self, id: MsgId) -> Result<bool, IoError> {
+        if self.get(id).await.is_none() {
+            return Ok(false);
+        }
+        self.append(LogEntry::Delete { id }).await?;
+
+        self.recent.lock().unwrap().remove(id);
+
+        Ok(true)
+    }
+
+    async fn react(
+        &self,
+        id: MsgId,
+        emoji: String,
+        handle: String,
+        on: bool,
+    ) -> Result<Option<Reactions>, IoError> {
+        let Some(mut old) = self.get(id).await else {
+            return Ok(None);
+        };
+        self.append(LogEntry::React {
+            id,
+            emoji: emoji.clone(),
+            handle: handle.clone(),
+            on,
+        })
+        .await?;
+
+        // messages only found in the log aren't kept around, so the one read back is changed
+        // instead
+        let mut recent = self.recent.lock().unwrap();
+        let msg = recent.get_mut(id).unwrap_or(&mut old);
+        msg.reactions.set(emoji, handle, on);
+        Ok(Some(msg.reactions.clone()))
+    }
+
+    async fn rename(&self, old: &str, new: &str) -> Result<(), IoError> {
+        self.append(LogEntry::Rename {
+            old: old.to_string(),
+            new: new.to_string(),
+        })
+        .await?;
+
+        self.recent.lock().unwrap().rename(old, new);
+
+        Ok(())
+    }
+}
+
+fn raise_last_id(last_id: &Mutex<Option<MsgId>>, id: MsgId) {
+    let mut last_id = last_id.lock().unwrap();
+    *last_id = (*last_id).max(Some(id));
+}
+
+fn last_matching<'a>(
+    msgs: impl DoubleEndedIterator<Item = &'a StoredMsg>,
+    pred: impl Fn(&StoredMsg) -> bool,
+    limit: usize,
+) -> Vec<StoredMsg> {
+    let mut last: Vec<_> = msgs
+        .rev()
+        .filter(|msg| pred(msg))
+        .take(limit)
+        .cloned()
+        .collect();
+    last.reverse();
+    last
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn msg(id: MsgId, room: &str) -> StoredMsg {
+        StoredMsg {
+            id,
+            timestamp: 1000 + id,
+            room: room.to_string(),
+            sender: "anne".to_string(),
+            msg: format!("msg {}", id),
+            reply_to: None,
+            mentions: Vec::new(),
+            reactions: Reactions::default(),
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("minichat-{}-{}.log", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[tokio::test]
+    async fn memory_store_is_a_ring_buffer() {
+        let store = MemoryStore::new(3);
+        for id in 0..5 {
+            store.push(msg(id, "lobby")).await.unwrap();
+        }
+
+        assert_eq!(
+            store.last("lobby", 10).await,
+            [msg(2, "lobby"), msg(3, "lobby"), msg(4, "lobby")]
+        );
+        assert_eq!(store.last_id(), Some(4));
+    }
+
+    #[tokio::test]
+    async fn last_filters_by_room() {
+        let store = MemoryStore::new(10);
+        store.push(msg(0, "lobby")).await.unwrap();
+        store.push(msg(1, "rust")).await.unwrap();
+        store.push(msg(2, "lobby")).await.unwrap();
+        store.push(msg(3, "lobby")).await.unwrap();
+
+        assert_eq!(
+            store.last("lobby", 2).await,
+            [msg(2, "lobby"), msg(3, "lobby")]
+        );
+        assert_eq!(store.last("rust", 2).await, [msg(1, "rust")]);
+        assert!(store.last("flutter", 2).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn history_pages() {
+        let store = MemoryStore::new(10);
+        for id in 0..6 {
+            store.push(msg(id, "lobby")).await.unwrap();
+        }
+        store.push(msg(6, "rust")).await.unwrap();
+
+        assert_eq!(
+            store.history("lobby", None, 2).await,
+            [msg(4, "lobby"), msg(5, "lobby")]
+        );
+        assert_eq!(
+            store.history("lobby", Some(4), 2).await,
+            [msg(2, "lobby"), msg(3, "lobby")]
+        );
+        assert_eq!(store.history("lobby", Some(1), 2).await, [msg(0, "lobby")]);
+        assert!(store.history("lobby", Some(0), 2).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn out_of_order_pushes() {
+        let store = MemoryStore::new(10);
+        store.push(msg(1, "lobby")).await.unwrap();
+        store.push(msg(0, "lobby")).await.unwrap();
+        store.push(msg(2, "lobby")).await.unwrap();
+
+        assert_eq!(
+            store.last("lobby", 10).await,
+            [msg(0, "lobby"), msg(1, "lobby"), msg(2, "lobby")]
+        );
+    }
+
+    #[tokio::test]
+    async fn file_store_persists() {
+        let path = temp_path("persists");
+
+        {
+            let store = FileStore::open(&path, 10).unwrap();
+            store.push(msg(0, "lobby")).await.unwrap();
+            store.push(msg(1, "rust")).await.unwrap();
+        }
+
+        let store = FileStore::open(&path, 10).unwrap();
+        assert_eq!(store.last("lobby", 10).await, [msg(0, "lobby")]);
+        assert_eq!(store.last("rust", 10).await, [msg(1, "rust")]);
+        assert_eq!(store.last_id(), Some(1));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    fn reply(id: MsgId, reply_to: MsgId) -> StoredMsg {
+        StoredMsg {
+            reply_to: Some(reply_to),
+            ..msg(id, "lobby")
+        }
+    }
+
+    #[tokio::test]
+    async fn replies() {
+        let store = MemoryStore::new(10);
+        store.push(msg(0, "lobby")).await.unwrap();
+        store.push(msg(1, "lobby")).await.unwrap();
+        for id in 2..5 {
+            store.push(reply(id, 0)).await.unwrap();
+        }
+        store.push(reply(5, 1)).await.unwrap();
+
+        assert_eq!(store.replies(0, None, 2).await, [reply(3, 0), reply(4, 0)]);
+        assert_eq!(store.replies(0, Some(3), 2).await, [reply(2, 0)]);
+        assert_eq!(store.replies(1, None, 10).await, [reply(5, 1)]);
+        assert!(store.replies(5, None, 10).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn file_store_persists_replies_and_mentions() {
+        let path = temp_path("replies");
+
+        let mention = StoredMsg {
+            mentions: vec!["bob".to_string()],
+            ..msg(2, "lobby")
+        };
+        {
+            let store = FileStore::open(&path, 10).unwrap();
+            store.push(msg(0, "lobby")).await.unwrap();
+            store.push(reply(1, 0)).await.unwrap();
+            store.push(mention.clone()).await.unwrap();
+        }
+
+        let store = FileStore::open(&path, 10).unwrap();
+        assert_eq!(
+            store.last("lobby", 10).await,
+            [msg(0, "lobby"), reply(1, 0), mention]
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn edit_and_delete() {
+        let store = MemoryStore::new(10);
+        for id in 0..3 {
+            store.push(msg(id, "lobby")).await.unwrap();
+        }
+
+        let mentions = vec!["anne".to_string()];
+        assert!(store
+            .edit(1, "edited".to_string(), mentions.clone())
+            .await
+            .unwrap());
+        assert!(store.delete(2).await.unwrap());
+        assert!(!store.edit(2, "gone".to_string(), vec![]).await.unwrap());
+        assert!(!store.delete(7).await.unwrap());
+
+        let edited = StoredMsg {
+            msg: "edited".to_string(),
+            mentions,
+            ..msg(1, "lobby")
+        };
+        assert_eq!(store.get(1).await, Some(edited.clone()));
+        assert_eq!(store.get(2).await, None);
+        assert_eq!(store.last("lobby", 10).await, [msg(0, "lobby"), edited]);
+        assert_eq!(store.last_id(), Some(2));
+    }
+
+    #[tokio::test]
+    async fn file_store_persists_edits() {
+        let path = temp_path("edits");
+
+        {
+            let store = FileStore::open(&path, 10).unwrap();
+            store.push(msg(0, "lobby")).await.unwrap();
+            store.push(msg(1, "lobby")).await.unwrap();
+            let mentions = vec!["anne".to_string()];
+            store.edit(0, "edited".to_string(), mentions).await.unwrap();
+            store.delete(1).await.unwrap();
+        }
+
+        let store = FileStore::open(&path, 10).unwrap();
+        let edited = StoredMsg {
+            msg: "edited".to_string(),
+            mentions: vec!["anne".to_string()],
+            ..msg(0, "lobby")
+        };
+        assert_eq!(store.last("lobby", 10).await, [edited]);
+        // the deleted message's id isn't up for grabs again
+        assert_eq!(store.last_id(), Some(1));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reactions() {
+        let store = MemoryStore::new(10);
+        store.push(msg(0, "lobby")).await.unwrap();
+
+        let react = |emoji: &str, handle: &str, on| {
+            store.react(0, emoji.to_string(), handle.to_string(), on)
+        };
+        react("👍", "anne", true).await.unwrap();
+        react("👍", "anne", true).await.unwrap();
+        react("👍", "bob", true).await.unwrap();
+        react("🎉", "bob", true).await.unwrap();
+        let reactions = react("🎉", "bob", false).await.unwrap().unwrap();
+
+        assert_eq!(
+            reactions.counts(),
+            [Reaction {
+                emoji: "👍".to_string(),
+                count: 2
+            }]
+        );
+        assert!(react("👍", "anne", true).await.unwrap().is_some());
+        assert!(store
+            .react(1, "👍".to_string(), "anne".to_string(), true)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn file_store_persists_reactions() {
+        let path = temp_path("reactions");
+
+        {
+            let store = FileStore::open(&path, 10).unwrap();
+            store.push(msg(0, "lobby")).await.unwrap();
+            for handle in ["anne", "bob"] {
+                store
+                    .react(0, "👍".to_string(), handle.to_string(), true)
+                    .await
+                    .unwrap();
+            }
+            store.rename("bob", "robert").await.unwrap();
+        }
+
+        let store = FileStore::open(&path, 10).unwrap();
+        let reactions = store.get(0).await.unwrap().reactions;
+        assert_eq!(
+            reactions.counts(),
+            [Reaction {
+                emoji: "👍".to_string(),
+                count: 2
+            }]
+        );
+        // the reaction moved along with the handle
+        let reactions = store
+            .react(0, "👍".to_string(), "robert".to_string(), false)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(reactions.counts()[0].count, 1);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn file_store_truncates_torn_entry() {
+        let path = temp_path("torn");
+
+        {
+            let store = FileStore::open(&path, 10).unwrap();
+            store.push(msg(0, "lobby")).await.unwrap();
+        }
+        let valid_len = std::fs::metadata(&path).unwrap().len();
+        {
+            use std::io::Write as _;
+            let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+            file.write_all(&[0, 1, 0]).unwrap();
+        }
+
+        let store = FileStore::open(&path, 10).unwrap();
+        assert_eq!(store.last("lobby", 10).await, [msg(0, "lobby")]);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), valid_len);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn file_store_rejects_corruption() {
+        let path = temp_path("corrupted");
+
+        {
+            let store = FileStore::open(&path, 10).unwrap();
+            store.push(msg(0, "lobby")).await.unwrap();
+            store.push(msg(1, "lobby")).await.unwrap();
+        }
+        let mut bytes = std::fs::read(&path).unwrap();
+        // the tag of the first entry, right after its length
+        bytes[4] = 0xff;
+        std::fs::write(&path, &bytes).unwrap();
+
+        let err = FileStore::open(&path, 10).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(std::fs::read(&path).unwrap(), bytes);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn file_store_reads_older_msgs_from_the_log() {
+        let path = temp_path("older");
+
+        let store = FileStore::open(&path, 2).unwrap();
+        for id in 0..6 {
+            let room = if id % 2 == 0 { "lobby" } else { "rust" };
+            store.push(msg(id, room)).await.unwrap();
+        }
+        store.push(reply(6, 1)).await.unwrap();
+        assert!(store.edit(0, "edited".to_string(), vec![]).await.unwrap());
+        assert!(store.delete(2).await.unwrap());
+        let reactions = store
+            .react(0, "👍".to_string(), "bob".to_string(), true)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(reactions.counts().len(), 1);
+
+        let mut edited = StoredMsg {
+            msg: "edited".to_string(),
+            ..msg(0, "lobby")
+        };
+        edited.reactions = reactions;
+        for store in [store, FileStore::open(&path, 2).unwrap()] {
+            assert_eq!(store.recent.lock().unwrap().msgs.len(), 2);
+            assert_eq!(
+                store.last("lobby", 10).await,
+                [edited.clone(), msg(4, "lobby"), reply(6, 1)]
+            );
+            assert_eq!(store.history("rust", Some(5), 1).await, [msg(3, "rust")]);
+            assert_eq!(store.replies(1, None, 10).await, [reply(6, 1)]);
+            assert_eq!(store.get(0).await, Some(edited.clone()));
+            assert_eq!(store.get(2).await, None);
+            assert_eq!(store.last_id(), Some(6));
+        }
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
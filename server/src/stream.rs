@@ -1,30 +1,51 @@
-use std::future;
+use futures_util::{stream, Sink, SinkExt, Stream, StreamExt as _};
 
-use futures_util::{Sink, SinkExt, Stream, StreamExt as _};
+use crate::codec::Codec;
+use crate::frame::{ClientFrame, DecodeError, EncodeError, ServerFrame};
 
-use crate::frame::{ClientFrame, DecodeError, ServerFrame};
+/// A message of the underlying transport (e.g. a WebSocket message) that carries a single frame.
+pub trait TransportMsg: Sized {
+    /// Returns `None` for messages that only show the client is still there (e.g. a WebSocket
+    /// pong).
+    fn into_client_frame(self, codec: &Codec) -> Result<Option<ClientFrame>, DecodeError>;
 
-//pub type ClientStream = Box<dyn Stream<Item = Result<ClientFrame, DecodeError>>>;
+    /// Returns `None` for frames that shouldn't be sent to this client at all.
+    fn from_server_frame(frame: ServerFrame, codec: &Codec) -> Result<Option<Self>, EncodeError>;
 
-pub fn wrap_client_stream<S, M, E>(s: S) -> impl Stream<Item = Result<ClientFrame, DecodeError>>
+    /// A message to send right after frames that end the connection, for transports that have
+    /// their own way of saying goodbye (e.g. a WebSocket close frame).
+    fn closing(_frame: &ServerFrame) -> Option<Self> {
+        None
+    }
+}
+
+//pub type ClientStream = Box<dyn Stream<Item = Result<Option<ClientFrame>, DecodeError>>>;
+
+pub fn wrap_client_stream<S, M, E>(
+    s: S,
+    codec: Codec,
+) -> impl Stream<Item = Result<Option<ClientFrame>, DecodeError>>
 where
     S: Stream<Item = Result<M, E>> + 'static,
-    M: TryInto<ClientFrame, Error = DecodeError>,
+    M: TransportMsg,
     E: Into<DecodeError>,
 {
-    s.map(|item| match item {
-        Ok(m) => m.try_into(),
+    s.map(move |item| match item {
+        Ok(m) => m.into_client_frame(&codec),
         Err(e) => Err(e.into()),
     })
 }
 
 // pub type ClientSink = Box<dyn Sink<ServerFrame, Error = ()>>;
 
-pub fn wrap_client_sink<S, M>(s: S) -> impl Sink<ServerFrame, Error = ()>
+pub fn wrap_client_sink<S, M>(s: S, codec: Codec) -> impl Sink<ServerFrame, Error = ()>
 where
     S: Sink<M> + 'static,
-    M: TryFrom<ServerFrame, Error = ()> + 'static,
+    M: TransportMsg + 'static,
 {
-    s.sink_map_err(|_| ())
-        .with(|x: ServerFrame| future::ready(x.try_into()))
+    s.sink_map_err(|_| ()).with_flat_map(move |x: ServerFrame| {
+        let closing = M::closing(&x);
+        let msg = M::from_server_frame(x, &codec).map_err(|_| ()).transpose();
+        stream::iter(msg.into_iter().chain(closing.map(Ok)))
+    })
 }
@@ -0,0 +1,92 @@
+//! TLS termination, so clients can connect with `wss://` without a reverse proxy in between.
+
+use std::fs::File;
+use std::io::{BufReader, Error as IoError, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+/// A certificate and private key read from PEM files, which can be read again when they're
+/// renewed. Connections that are already established keep using the old ones.
+pub struct TlsCerts {
+    cert_file: PathBuf,
+    key_file: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl TlsCerts {
+    pub fn load(
+        cert_file: impl Into<PathBuf>,
+        key_file: impl Into<PathBuf>,
+    ) -> Result<Self, IoError> {
+        let cert_file = cert_file.into();
+        let key_file = key_file.into();
+        let current = read_certified_key(&cert_file, &key_file)?;
+
+        Ok(Self {
+            cert_file,
+            key_file,
+            current: RwLock::new(Arc::new(current)),
+        })
+    }
+
+    /// Reads the certificate and key again. If that fails, the old ones stay in use.
+    pub fn reload(&self) -> Result<(), IoError> {
+        let new = read_certified_key(&self.cert_file, &self.key_file)?;
+        *self.current.write().unwrap() = Arc::new(new);
+        Ok(())
+    }
+
+    pub fn cert_file(&self) -> &Path {
+        &self.cert_file
+    }
+
+    pub fn acceptor(self: Arc<Self>) -> TlsAcceptor {
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(self);
+        TlsAcceptor::from(Arc::new(config))
+    }
+}
+
+impl ResolvesServerCert for TlsCerts {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+fn read_certified_key(cert_file: &Path, key_file: &Path) -> Result<CertifiedKey, IoError> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_file)?))?;
+    if certs.is_empty() {
+        return Err(invalid_data(format!(
+            "no certificates in {}",
+            cert_file.display()
+        )));
+    }
+
+    let key = rustls_pemfile::read_all(&mut BufReader::new(File::open(key_file)?))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(key),
+            _ => None,
+        })
+        .ok_or_else(|| invalid_data(format!("no private key in {}", key_file.display())))?;
+    let key = rustls::sign::any_supported_type(&PrivateKey(key))
+        .map_err(|e| invalid_data(format!("{}: {}", key_file.display(), e)))?;
+
+    Ok(CertifiedKey::new(
+        certs.into_iter().map(Certificate).collect(),
+        key,
+    ))
+}
+
+fn invalid_data(msg: String) -> IoError {
+    IoError::new(ErrorKind::InvalidData, msg)
+}
@@ -1,18 +1,20 @@
 // This doesn't exist for testing any functionality, but just to keep around
 // some test vectors and make sure they're up to date.
+//
+// Protocol version 1 uses single byte request ids, hence the `u8` type parameters.
 
 #[test]
 fn server_frames() {
     use borsh::BorshSerialize as _;
     use minichat_server::frame::ServerFrame;
 
-    let frame = ServerFrame::Okay(2);
+    let frame = ServerFrame::<u8>::Okay(2);
     assert_eq!(frame.try_to_vec().unwrap(), [0, 2]);
 
-    let frame = ServerFrame::Err(2, "a".to_string());
+    let frame = ServerFrame::<u8>::Err(2, "a".to_string());
     assert_eq!(frame.try_to_vec().unwrap(), [1, 2, 1, 0, 0, 0, 97]);
 
-    let frame = ServerFrame::Broadcast {
+    let frame = ServerFrame::<u8>::BroadcastV1 {
         sender: "bob".to_string(),
         msg: "hi".to_string(),
     };
@@ -21,7 +23,7 @@ fn server_frames() {
         [2, 3, 0, 0, 0, 98, 111, 98, 2, 0, 0, 0, 104, 105]
     );
 
-    let frame = ServerFrame::Present("a".to_string());
+    let frame = ServerFrame::<u8>::PresentV1("a".to_string());
     assert_eq!(frame.try_to_vec().unwrap(), [3, 1, 0, 0, 0, 97]);
 }
 
@@ -30,17 +32,304 @@ fn client_frames() {
     use borsh::BorshDeserialize as _;
     use minichat_server::frame::{ClientFrame, ClientFrameType};
 
+    type ClientFrameV1 = ClientFrame<u8>;
+
     let login_bytes = [103, 0, 3, 0, 0, 0, 98, 111, 98];
-    let expected = ClientFrame {
+    let expected = ClientFrameV1 {
         id: 103,
         data: ClientFrameType::Login("bob".to_string()),
     };
-    assert_eq!(ClientFrame::try_from_slice(&login_bytes).unwrap(), expected);
+    assert_eq!(
+        ClientFrameV1::try_from_slice(&login_bytes).unwrap(),
+        expected
+    );
 
     let msg_bytes = [22, 1, 2, 0, 0, 0, 104, 105];
+    let expected = ClientFrameV1 {
+        id: 22,
+        data: ClientFrameType::MsgV1("hi".to_string()),
+    };
+    assert_eq!(ClientFrameV1::try_from_slice(&msg_bytes).unwrap(), expected);
+}
+
+/// Hellos are sent before a version has been agreed on, so they're always encoded like version 1.
+#[test]
+fn hello() {
+    use borsh::BorshDeserialize as _;
+    use minichat_server::frame::{ClientFrame, ClientFrameType};
+
+    let hello_bytes = [0, 7, 1, 0, 1, 0, 0, 0, 1, 0, 0, 0, 120];
+    let expected = ClientFrame::<u8> {
+        id: 0,
+        data: ClientFrameType::Hello {
+            protocol_version: 1,
+            capabilities: vec!["x".to_string()],
+        },
+    };
+    assert_eq!(
+        ClientFrame::<u8>::try_from_slice(&hello_bytes).unwrap(),
+        expected
+    );
+}
+
+#[test]
+fn server_frames_v2() {
+    use borsh::BorshSerialize as _;
+    use minichat_server::frame::ServerFrame;
+
+    let frame = ServerFrame::Okay(258);
+    assert_eq!(frame.try_to_vec().unwrap(), [0, 2, 1, 0, 0]);
+
+    let frame = ServerFrame::Err(2, "a".to_string());
+    assert_eq!(frame.try_to_vec().unwrap(), [1, 2, 0, 0, 0, 1, 0, 0, 0, 97]);
+
+    let frame: ServerFrame = ServerFrame::BroadcastV2 {
+        msg_id: 5,
+        timestamp: 256,
+        room: "r".to_string(),
+        sender: "bob".to_string(),
+        msg: "hi".to_string(),
+    };
+    assert_eq!(
+        frame.try_to_vec().unwrap(),
+        [
+            27, 5, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 114, 3, 0, 0, 0, 98,
+            111, 98, 2, 0, 0, 0, 104, 105
+        ]
+    );
+
+    let frame = ServerFrame::Receipt {
+        id: 2,
+        msg_id: 5,
+        timestamp: 256,
+    };
+    assert_eq!(
+        frame.try_to_vec().unwrap(),
+        [7, 2, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0]
+    );
+
+    let frame: ServerFrame = ServerFrame::Present {
+        room: "r".to_string(),
+        handle: "a".to_string(),
+    };
+    assert_eq!(
+        frame.try_to_vec().unwrap(),
+        [28, 1, 0, 0, 0, 114, 1, 0, 0, 0, 97]
+    );
+
+    let frame: ServerFrame = ServerFrame::Login {
+        room: "r".to_string(),
+        handle: "a".to_string(),
+    };
+    assert_eq!(
+        frame.try_to_vec().unwrap(),
+        [29, 1, 0, 0, 0, 114, 1, 0, 0, 0, 97]
+    );
+}
+
+#[test]
+fn server_frames_v3() {
+    use borsh::BorshSerialize as _;
+    use minichat_server::frame::{CloseReason, ErrorCode, ServerFrame};
+
+    let frame = ServerFrame::error(2, ErrorCode::HandleTaken, "a");
+    assert_eq!(
+        frame.try_to_vec().unwrap(),
+        [10, 2, 0, 0, 0, 2, 1, 0, 0, 0, 97]
+    );
+
+    let frame: ServerFrame = ServerFrame::ProtocolError {
+        code: ErrorCode::InvalidFrame,
+        msg: "a".to_string(),
+    };
+    assert_eq!(frame.try_to_vec().unwrap(), [11, 12, 1, 0, 0, 0, 97]);
+
+    let frame: ServerFrame = ServerFrame::Close {
+        reason: CloseReason::TooManyErrors,
+        msg: "a".to_string(),
+    };
+    assert_eq!(frame.try_to_vec().unwrap(), [12, 0, 1, 0, 0, 0, 97]);
+}
+
+#[test]
+fn server_frames_v4() {
+    use borsh::BorshSerialize as _;
+    use minichat_server::frame::ServerFrame;
+
+    let frame: ServerFrame = ServerFrame::BroadcastV4 {
+        msg_id: 5,
+        timestamp: 256,
+        room: "r".to_string(),
+        sender: "bob".to_string(),
+        msg: "hi".to_string(),
+        reply_to: Some(3),
+    };
+    assert_eq!(
+        frame.try_to_vec().unwrap(),
+        [
+            20, 5, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 114, 3, 0, 0, 0, 98,
+            111, 98, 2, 0, 0, 0, 104, 105, 1, 3, 0, 0, 0, 0, 0, 0, 0
+        ]
+    );
+}
+
+#[test]
+fn server_frames_v5() {
+    use borsh::BorshSerialize as _;
+    use minichat_server::frame::ServerFrame;
+
+    let frame: ServerFrame = ServerFrame::Broadcast {
+        msg_id: 5,
+        timestamp: 256,
+        room: "r".to_string(),
+        sender: "bob".to_string(),
+        msg: "hi".to_string(),
+        reply_to: None,
+        mentions: vec!["a".to_string()],
+    };
+    assert_eq!(
+        frame.try_to_vec().unwrap(),
+        [
+            22, 5, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 114, 3, 0, 0, 0, 98,
+            111, 98, 2, 0, 0, 0, 104, 105, 0, 1, 0, 0, 0, 1, 0, 0, 0, 97
+        ]
+    );
+}
+
+#[test]
+fn server_frames_v6() {
+    use borsh::BorshSerialize as _;
+    use minichat_server::frame::{LogoutReason, ServerFrame};
+
+    let frame: ServerFrame = ServerFrame::LogoutV2 {
+        room: "r".to_string(),
+        handle: "a".to_string(),
+    };
+    assert_eq!(
+        frame.try_to_vec().unwrap(),
+        [30, 1, 0, 0, 0, 114, 1, 0, 0, 0, 97]
+    );
+
+    let frame: ServerFrame = ServerFrame::Logout {
+        room: "r".to_string(),
+        handle: "a".to_string(),
+        reason: LogoutReason::Timeout,
+    };
+    assert_eq!(
+        frame.try_to_vec().unwrap(),
+        [25, 1, 0, 0, 0, 114, 1, 0, 0, 0, 97, 2]
+    );
+
+    let frame: ServerFrame = ServerFrame::Pong(258);
+    assert_eq!(frame.try_to_vec().unwrap(), [24, 2, 1, 0, 0]);
+
+    let frame: ServerFrame = ServerFrame::ServerShutdown {
+        reason: "a".to_string(),
+        reconnect_after: Some(30),
+    };
+    assert_eq!(
+        frame.try_to_vec().unwrap(),
+        [26, 1, 0, 0, 0, 97, 1, 30, 0, 0, 0]
+    );
+}
+
+#[test]
+fn server_frames_v8() {
+    use borsh::BorshSerialize as _;
+    use minichat_server::frame::{HistoryMsg, ServerFrame};
+
+    let frame = ServerFrame::HistoryPage {
+        id: 2,
+        room: "r".to_string(),
+        msgs: vec![HistoryMsg {
+            msg_id: 5,
+            timestamp: 256,
+            sender: "a".to_string(),
+            msg: "hi".to_string(),
+            reply_to: Some(3),
+        }],
+    };
+    assert_eq!(
+        frame.try_to_vec().unwrap(),
+        [
+            31, 2, 0, 0, 0, 1, 0, 0, 0, 114, 1, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0,
+            0, 0, 1, 0, 0, 0, 97, 2, 0, 0, 0, 104, 105, 1, 3, 0, 0, 0, 0, 0, 0, 0
+        ]
+    );
+}
+
+#[test]
+fn client_frames_v2() {
+    use borsh::BorshDeserialize as _;
+    use minichat_server::frame::{ClientFrame, ClientFrameType};
+
+    let login_bytes = [2, 1, 0, 0, 0, 3, 0, 0, 0, 98, 111, 98];
+    let expected = ClientFrame {
+        id: 258,
+        data: ClientFrameType::Login("bob".to_string()),
+    };
+    assert_eq!(ClientFrame::try_from_slice(&login_bytes).unwrap(), expected);
+
+    let msg_bytes = [22, 0, 0, 0, 18, 1, 0, 0, 0, 114, 2, 0, 0, 0, 104, 105];
     let expected = ClientFrame {
         id: 22,
-        data: ClientFrameType::Msg("hi".to_string()),
+        data: ClientFrameType::Msg {
+            room: "r".to_string(),
+            msg: "hi".to_string(),
+        },
     };
     assert_eq!(ClientFrame::try_from_slice(&msg_bytes).unwrap(), expected);
+
+    let history_bytes = [
+        24, 0, 0, 0, 6, 1, 0, 0, 0, 114, 1, 9, 0, 0, 0, 0, 0, 0, 0, 20, 0, 0, 0,
+    ];
+    let expected = ClientFrame {
+        id: 24,
+        data: ClientFrameType::History {
+            room: "r".to_string(),
+            before: Some(9),
+            limit: 20,
+        },
+    };
+    assert_eq!(
+        ClientFrame::try_from_slice(&history_bytes).unwrap(),
+        expected
+    );
+
+    let join_bytes = [23, 0, 0, 0, 3, 1, 0, 0, 0, 114];
+    let expected = ClientFrame {
+        id: 23,
+        data: ClientFrameType::Join("r".to_string()),
+    };
+    assert_eq!(ClientFrame::try_from_slice(&join_bytes).unwrap(), expected);
+}
+
+#[test]
+fn json_frames() {
+    use minichat_server::frame::{ClientFrame, ClientFrameType, ErrorCode, ServerFrame};
+
+    let frame: ClientFrame =
+        serde_json::from_str(r#"{"id":300,"data":{"msg":{"room":"r","msg":"hi"}}}"#).unwrap();
+    assert_eq!(
+        frame,
+        ClientFrame {
+            id: 300,
+            data: ClientFrameType::Msg {
+                room: "r".to_string(),
+                msg: "hi".to_string(),
+            },
+        }
+    );
+
+    let frame: ClientFrame = serde_json::from_str(r#"{"id":2,"data":"logout"}"#).unwrap();
+    assert_eq!(frame.data, ClientFrameType::Logout);
+
+    let frame: ServerFrame = ServerFrame::Okay(300);
+    assert_eq!(serde_json::to_string(&frame).unwrap(), r#"{"okay":300}"#);
+
+    let frame: ServerFrame = ServerFrame::error(2, ErrorCode::HandleTaken, "a");
+    assert_eq!(
+        serde_json::to_string(&frame).unwrap(),
+        r#"{"error":{"id":2,"code":"handle_taken","msg":"a"}}"#
+    );
 }
@@ -1,18 +1,39 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::{sync::Mutex, time::Duration};
 
 use futures_util::{stream::ReadyChunks, SinkExt, StreamExt};
 use lazy_static::lazy_static;
 use minichat_server::{
-    frame::{ClientFrame, ClientFrameType, ServerFrame},
-    protocol::ws::ws_sink_stream,
-    serve_tcp,
+    codec::Codec,
+    config::Config,
+    frame::{
+        ClientFrame, ClientFrameType, ErrorCode, MsgId, Presence, RequestId, ServerFrame,
+        DEFAULT_ROOM, PROTOCOL_VERSION,
+    },
+    protocol::{
+        tcp::tcp_sink_stream,
+        ws::{ws_sink_stream, ws_sink_stream_with_ping_interval},
+    },
+    serve_tcp, serve_tcp_until, Server, Shutdown,
 };
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
 use tokio::select;
-use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+use tokio_tungstenite::{
+    connect_async, connect_async_tls_with_config, Connector, MaybeTlsStream, WebSocketStream,
+};
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::Message as WsMessage;
 
 type Stream = WebSocketStream<MaybeTlsStream<TcpStream>>;
 
+/// How long to wait for frames that are expected to arrive. Checking that a frame doesn't arrive
+/// only waits until nothing has come in for a moment, see [`Client::collect_incoming`].
+const EXPECT_TIMEOUT: Duration = Duration::from_secs(5);
+
 struct SocketProvider {
     cur: Mutex<u32>,
 }
@@ -29,6 +50,21 @@ impl SocketProvider {
         *lock += 1;
         format!("127.0.0.1:{}", *lock)
     }
+
+    fn issue_unix(&self) -> PathBuf {
+        self.issue_file("sock")
+    }
+
+    fn issue_file(&self, extension: &str) -> PathBuf {
+        let mut lock = self.cur.lock().unwrap();
+        *lock += 1;
+        std::env::temp_dir().join(format!(
+            "minichat-{}-{}.{}",
+            std::process::id(),
+            *lock,
+            extension
+        ))
+    }
 }
 
 lazy_static! {
@@ -36,10 +72,128 @@ lazy_static! {
 }
 
 pub async fn run_ws_server() -> String {
+    run_ws_server_with_config(Config::default()).await
+}
+
+pub async fn run_ws_server_with_config(config: Config) -> String {
+    let url = SOCKET_PROVIDER.issue();
+    let url_c = url.clone();
+    tokio::spawn(async move {
+        serve_tcp(&url_c, config, ws_sink_stream).await.unwrap();
+    });
+
+    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+    url
+}
+
+/// Runs a WebSocket server that pings clients every `ping_interval`.
+pub async fn run_pinging_ws_server(config: Config, ping_interval: Duration) -> String {
+    let url = SOCKET_PROVIDER.issue();
+    let url_c = url.clone();
+    tokio::spawn(async move {
+        let transport = move |stream| ws_sink_stream_with_ping_interval(stream, ping_interval);
+        serve_tcp(&url_c, config, transport).await.unwrap();
+    });
+
+    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+    url
+}
+
+pub async fn run_tcp_server() -> String {
     let url = SOCKET_PROVIDER.issue();
     let url_c = url.clone();
     tokio::spawn(async move {
-        serve_tcp(&url_c, ws_sink_stream).await.unwrap();
+        serve_tcp(&url_c, Config::default(), tcp_sink_stream)
+            .await
+            .unwrap();
+    });
+
+    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+    url
+}
+
+/// Runs a server with a WebSocket and a raw TCP listener, in that order.
+pub async fn run_mixed_server() -> (String, String) {
+    let ws_url = SOCKET_PROVIDER.issue();
+    let tcp_url = SOCKET_PROVIDER.issue();
+    let server = Server::new(Config::default())
+        .unwrap()
+        .listen_tcp(ws_url.clone(), ws_sink_stream)
+        .listen_tcp(tcp_url.clone(), tcp_sink_stream);
+    tokio::spawn(async move {
+        server.serve().await.unwrap();
+    });
+
+    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+    (ws_url, tcp_url)
+}
+
+/// Runs a server with a length-prefixed Unix domain socket listener.
+pub async fn run_unix_server() -> PathBuf {
+    let path = SOCKET_PROVIDER.issue_unix();
+    let server = Server::new(Config::default())
+        .unwrap()
+        .listen_unix(path.clone(), tcp_sink_stream);
+    tokio::spawn(async move {
+        server.serve().await.unwrap();
+    });
+
+    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+    path
+}
+
+/// A self-signed certificate for `localhost`, written to PEM files.
+pub struct TestCert {
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+}
+
+impl TestCert {
+    pub fn new() -> Self {
+        Self {
+            cert_file: SOCKET_PROVIDER.issue_file("crt"),
+            key_file: SOCKET_PROVIDER.issue_file("key"),
+        }
+    }
+
+    /// Replaces the files with a brand new certificate and returns it in DER.
+    pub fn renew(&self) -> Vec<u8> {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let pem = cert.serialize_pem().unwrap();
+        std::fs::write(&self.cert_file, &pem).unwrap();
+        std::fs::write(&self.key_file, cert.serialize_private_key_pem()).unwrap();
+
+        rustls_pemfile::certs(&mut pem.as_bytes())
+            .unwrap()
+            .remove(0)
+    }
+}
+
+impl Drop for TestCert {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.cert_file);
+        let _ = std::fs::remove_file(&self.key_file);
+    }
+}
+
+/// Runs a server with a TLS WebSocket listener. Connect to it with [`Client::connect_tls`].
+pub async fn run_tls_server(cert: &TestCert) -> String {
+    let url = SOCKET_PROVIDER.issue();
+    let config = Config {
+        tls_cert_file: Some(cert.cert_file.clone()),
+        tls_key_file: Some(cert.key_file.clone()),
+        ..Config::default()
+    };
+    let server = Server::new(config)
+        .unwrap()
+        .listen_tls(url.clone(), ws_sink_stream);
+    tokio::spawn(async move {
+        server.serve().await.unwrap();
     });
 
     tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
@@ -47,73 +201,515 @@ pub async fn run_ws_server() -> String {
     url
 }
 
+/// A fresh path for a Unix domain socket.
+pub fn unix_socket_path() -> PathBuf {
+    SOCKET_PROVIDER.issue_unix()
+}
+
+/// Runs a server with a length-prefixed Unix domain socket listener at `path`, which shuts down
+/// once something is sent on the returned channel.
+pub async fn run_stoppable_unix_server(path: PathBuf) -> oneshot::Sender<Shutdown> {
+    let (tx, rx) = oneshot::channel();
+    let server = Server::new(Config::default())
+        .unwrap()
+        .listen_unix(path, tcp_sink_stream);
+    tokio::spawn(async move {
+        let shutdown = async { rx.await.unwrap() };
+        server.serve_until(shutdown).await.unwrap();
+    });
+
+    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+    tx
+}
+
+/// Runs a server that shuts down once something is sent on the returned channel.
+pub async fn run_stoppable_ws_server(config: Config) -> (String, oneshot::Sender<Shutdown>) {
+    let url = SOCKET_PROVIDER.issue();
+    let url_c = url.clone();
+    let (tx, rx) = oneshot::channel();
+    tokio::spawn(async move {
+        let shutdown = async { rx.await.unwrap() };
+        serve_tcp_until(&url_c, config, ws_sink_stream, shutdown)
+            .await
+            .unwrap();
+    });
+
+    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+    (url, tx)
+}
+
 pub struct Client {
     stream: ReadyChunks<Stream>,
     incoming: Vec<ServerFrame>,
-    msg_count: u8,
+    closed: bool,
+    close_code: Option<CloseCode>,
+    codec: Codec,
+    /// Whether frames are sent as JSON in text messages, rather than binary ones.
+    json: bool,
+    msg_count: RequestId,
 }
 
 impl Client {
+    /// Connect, say hello and log in.
     pub async fn new(handle: &str, url: &str) -> Self {
+        let mut client = Self::connect(url).await;
+        client.hello(PROTOCOL_VERSION).await;
+        client.login(handle).await;
+
+        client
+    }
+
+    pub async fn connect(url: &str) -> Self {
         let url = format!("ws://{}", url);
         let (stream, _) = connect_async(url).await.expect("Failed to connect");
 
-        let mut client = Self {
+        Self {
             stream: stream.ready_chunks(100),
             incoming: Vec::new(),
+            closed: false,
+            close_code: None,
+            codec: Codec::new(),
+            json: false,
             msg_count: 0,
-        };
+        }
+    }
+
+    /// Connect and send frames as JSON from now on.
+    pub async fn connect_json(url: &str) -> Self {
+        Self {
+            json: true,
+            ..Self::connect(url).await
+        }
+    }
+
+    /// Connect with TLS, trusting only the given certificate.
+    pub async fn connect_tls(url: &str, cert: &[u8]) -> Result<Self, tungstenite::Error> {
+        let port = url.rsplit(':').next().unwrap();
+        let url = format!("wss://localhost:{}", port);
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(&rustls::Certificate(cert.to_vec())).unwrap();
+        let config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = Connector::Rustls(Arc::new(config));
+        let (stream, _) = connect_async_tls_with_config(url, None, Some(connector)).await?;
+
+        Ok(Self {
+            stream: stream.ready_chunks(100),
+            incoming: Vec::new(),
+            closed: false,
+            close_code: None,
+            codec: Codec::new(),
+            json: false,
+            msg_count: 0,
+        })
+    }
 
-        let login = client
-            .send_frame(ClientFrameType::Login(handle.to_string()))
+    /// Say hello and return the server's response.
+    pub async fn hello(&mut self, protocol_version: u16) -> ServerFrame {
+        let id = self
+            .send_frame(ClientFrameType::Hello {
+                protocol_version,
+                capabilities: Vec::new(),
+            })
             .await;
-        client.assert_frame(ServerFrame::Okay(login)).await;
+        self.expect_frame(&format!("response to hello {}", id), |f| {
+            matches!(f, ServerFrame::Welcome { id: r_id, .. } | ServerFrame::Err(r_id, _) if *r_id == id)
+        })
+        .await
+    }
 
-        client
+    pub async fn login(&mut self, handle: &str) {
+        let login = self.send_login(handle).await;
+        self.assert_frame(ServerFrame::Okay(login)).await;
     }
 
-    pub async fn send_frame(&mut self, frame: ClientFrameType) -> u8 {
+    pub async fn send_login(&mut self, handle: &str) -> RequestId {
+        self.send_frame(ClientFrameType::Login(handle.to_string()))
+            .await
+    }
+
+    pub async fn send_frame(&mut self, frame: ClientFrameType) -> RequestId {
         let id = self.msg_count;
+        let frame = ClientFrame { id, data: frame };
+        let msg = if self.json {
+            WsMessage::Text(self.codec.encode_client_frame_json(frame).unwrap())
+        } else {
+            WsMessage::Binary(self.codec.encode_client_frame(frame).unwrap())
+        };
+        self.stream.send(msg).await.unwrap();
+        self.msg_count += 1;
+        id
+    }
+
+    /// Send bytes that might not be a valid frame at all.
+    pub async fn send_raw(&mut self, bytes: &[u8]) {
         self.stream
-            .send(ClientFrame { id, data: frame }.try_into().unwrap())
+            .send(WsMessage::Binary(bytes.to_vec()))
             .await
             .unwrap();
-        self.msg_count += 1;
-        id
     }
 
-    pub async fn send_msg(&mut self, msg: &str) -> u8 {
-        self.send_frame(ClientFrameType::Msg(msg.to_string())).await
+    /// Skip ahead to test request ids that don't fit in older protocol versions.
+    pub fn set_next_request_id(&mut self, id: RequestId) {
+        self.msg_count = id;
+    }
+
+    pub async fn send_msg(&mut self, msg: &str) -> RequestId {
+        self.send_room_msg(DEFAULT_ROOM, msg).await
+    }
+
+    pub async fn send_room_msg(&mut self, room: &str, msg: &str) -> RequestId {
+        self.send_frame(ClientFrameType::Msg {
+            room: room.to_string(),
+            msg: msg.to_string(),
+        })
+        .await
+    }
+
+    pub async fn send_direct_msg(&mut self, to: &str, msg: &str) -> RequestId {
+        self.send_frame(ClientFrameType::DirectMsg {
+            to: to.to_string(),
+            msg: msg.to_string(),
+        })
+        .await
+    }
+
+    /// Fetch a page of history and return the messages as `(sender, msg)` pairs.
+    pub async fn fetch_history(
+        &mut self,
+        room: &str,
+        before: Option<MsgId>,
+        limit: u32,
+    ) -> Vec<(String, String)> {
+        let id = self
+            .send_frame(ClientFrameType::History {
+                room: room.to_string(),
+                before,
+                limit,
+            })
+            .await;
+        match self
+            .expect_frame(
+                &format!("history page {}", id),
+                |f| matches!(f, ServerFrame::HistoryPage { id: p_id, .. } if *p_id == id),
+            )
+            .await
+        {
+            ServerFrame::HistoryPage { msgs, .. } => {
+                msgs.into_iter().map(|m| (m.sender, m.msg)).collect()
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub async fn edit(&mut self, msg_id: MsgId, new_text: &str) -> RequestId {
+        self.send_frame(ClientFrameType::Edit {
+            msg_id,
+            new_text: new_text.to_string(),
+        })
+        .await
+    }
+
+    pub async fn delete(&mut self, msg_id: MsgId) -> RequestId {
+        self.send_frame(ClientFrameType::Delete { msg_id }).await
+    }
+
+    pub async fn react(&mut self, msg_id: MsgId, emoji: &str, on: bool) -> RequestId {
+        self.send_frame(ClientFrameType::React {
+            msg_id,
+            emoji: emoji.to_string(),
+            on,
+        })
+        .await
+    }
+
+    pub async fn set_status(&mut self, presence: Presence, status: Option<&str>) {
+        let id = self
+            .send_frame(ClientFrameType::SetStatus {
+                presence,
+                status: status.map(str::to_string),
+            })
+            .await;
+        self.assert_frame(ServerFrame::Okay(id)).await;
+    }
+
+    pub async fn send_reply(&mut self, reply_to: MsgId, msg: &str) -> RequestId {
+        self.send_frame(ClientFrameType::Reply {
+            reply_to,
+            msg: msg.to_string(),
+        })
+        .await
+    }
+
+    /// Fetch replies to a message and return them as `(sender, msg)` pairs.
+    pub async fn fetch_thread(&mut self, msg_id: MsgId) -> Vec<(String, String)> {
+        let id = self
+            .send_frame(ClientFrameType::Thread {
+                msg_id,
+                before: None,
+                limit: 100,
+            })
+            .await;
+        match self
+            .expect_frame(
+                &format!("thread page {}", id),
+                |f| matches!(f, ServerFrame::ThreadPage { id: p_id, .. } if *p_id == id),
+            )
+            .await
+        {
+            ServerFrame::ThreadPage { msgs, .. } => {
+                msgs.into_iter().map(|m| (m.sender, m.msg)).collect()
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub async fn join(&mut self, room: &str) {
+        let id = self
+            .send_frame(ClientFrameType::Join(room.to_string()))
+            .await;
+        self.assert_frame(ServerFrame::Okay(id)).await;
+    }
+
+    pub async fn leave(&mut self, room: &str) {
+        let id = self
+            .send_frame(ClientFrameType::Leave(room.to_string()))
+            .await;
+        self.assert_frame(ServerFrame::Okay(id)).await;
     }
 
     pub async fn assert_frame(&mut self, exp_frame: ServerFrame) {
-        self.collect_incoming().await;
-        if !self.incoming.iter().find(|f| f == &&exp_frame).is_some() {
-            panic!("frame not received: {:?}", exp_frame);
+        self.expect_frame(&format!("{:?}", exp_frame), |f| f == &exp_frame)
+            .await;
+    }
+
+    pub async fn assert_no_frame(&mut self, frame: ServerFrame) {
+        self.assert_no_frame_matching(&format!("{:?}", frame), |f| f == &frame)
+            .await;
+    }
+
+    /// Wait for a frame matching the predicate and return it.
+    pub async fn expect_frame<P>(&mut self, desc: &str, pred: P) -> ServerFrame
+    where
+        P: Fn(&ServerFrame) -> bool,
+    {
+        let deadline = Instant::now() + EXPECT_TIMEOUT;
+        loop {
+            if let Some(frame) = self.incoming.iter().find(|f| pred(f)) {
+                return frame.clone();
+            }
+            if self.closed || Instant::now() >= deadline {
+                panic!("frame not received: {}", desc);
+            }
+            self.collect_incoming().await;
+        }
+    }
+
+    pub async fn assert_no_frame_matching<P>(&mut self, desc: &str, pred: P)
+    where
+        P: Fn(&ServerFrame) -> bool,
+    {
+        while self.collect_incoming().await {}
+        if self.incoming.iter().any(pred) {
+            panic!("unexpected frame received: {}", desc);
         }
     }
 
-    pub async fn assert_broadcast(&mut self, exp_sender: &str, exp_msg: &str) {
-        self.assert_frame(ServerFrame::Broadcast {
-            sender: exp_sender.to_string(),
-            msg: exp_msg.to_string(),
+    /// Forget the frames received so far, so that later assertions only look at new ones.
+    pub async fn forget_incoming(&mut self) {
+        while self.collect_incoming().await {}
+        self.incoming.clear();
+    }
+
+    pub async fn assert_error(&mut self, id: RequestId, code: ErrorCode) {
+        self.expect_frame(&format!("{:?} error for {}", code, id), |f| {
+            matches!(f, ServerFrame::Error { id: e_id, code: e_code, .. }
+                if *e_id == id && *e_code == code)
         })
         .await;
     }
 
+    /// Wait for the receipt of a message sent with [`Client::send_msg`] and return the id
+    /// the server assigned to it.
+    pub async fn expect_receipt(&mut self, id: RequestId) -> MsgId {
+        match self
+            .expect_frame(
+                &format!("receipt for {}", id),
+                |f| matches!(f, ServerFrame::Receipt { id: r_id, .. } if *r_id == id),
+            )
+            .await
+        {
+            ServerFrame::Receipt { msg_id, .. } => msg_id,
+            _ => unreachable!(),
+        }
+    }
+
+    pub async fn assert_broadcast(&mut self, exp_sender: &str, exp_msg: &str) -> MsgId {
+        self.assert_room_broadcast(DEFAULT_ROOM, exp_sender, exp_msg)
+            .await
+    }
+
+    /// Wait for a broadcast message and return its id.
+    pub async fn assert_room_broadcast(
+        &mut self,
+        exp_room: &str,
+        exp_sender: &str,
+        exp_msg: &str,
+    ) -> MsgId {
+        let desc = format!("{} in {}: {}", exp_sender, exp_room, exp_msg);
+        match self
+            .expect_frame(&desc, |f| is_broadcast(f, exp_room, exp_sender, exp_msg))
+            .await
+        {
+            ServerFrame::Broadcast { msg_id, .. } => msg_id,
+            _ => unreachable!(),
+        }
+    }
+
+    pub async fn assert_no_room_broadcast(&mut self, room: &str, sender: &str, msg: &str) {
+        let desc = format!("{} in {}: {}", sender, room, msg);
+        self.assert_no_frame_matching(&desc, |f| is_broadcast(f, room, sender, msg))
+            .await;
+    }
+
+    /// Keep reading for a while, which also answers the server's pings.
+    pub async fn keep_reading(&mut self, duration: Duration) {
+        let deadline = Instant::now() + duration;
+        while !self.closed && Instant::now() < deadline {
+            self.collect_incoming().await;
+        }
+    }
+
+    /// Wait for the server to close the connection.
+    pub async fn assert_closed(&mut self) {
+        let deadline = Instant::now() + EXPECT_TIMEOUT;
+        while !self.closed {
+            if Instant::now() >= deadline {
+                panic!("connection not closed");
+            }
+            self.collect_incoming().await;
+        }
+    }
+
+    /// Wait for the server to close the connection with a WebSocket close frame.
+    pub async fn assert_closed_with(&mut self, code: CloseCode) {
+        self.assert_closed().await;
+        assert_eq!(self.close_code, Some(code));
+    }
+
     pub async fn close(mut self) {
         self.stream.close().await.unwrap();
     }
 
     /// Collect ready incoming messages from the server and store them
-    /// for later inspection.
-    async fn collect_incoming(&mut self) {
+    /// for later inspection. Returns `false` if nothing came in.
+    async fn collect_incoming(&mut self) -> bool {
         // Time out if waiting for ready messages for more than 50 miliseconds,
         // since that probably means nothing is coming.
         //
         // There's probably a better way to do this by digging into async internals.
-        select!(Some(v) = self.stream.next() => self.incoming
-                .extend(v.into_iter().map(|res| res.unwrap().try_into().unwrap())),
-                _ = tokio::time::sleep(Duration::from_millis(50)) => {});
+        select!(v = self.stream.next() => match v {
+                    Some(v) => {
+                        for res in v {
+                            match res {
+                                Ok(WsMessage::Close(frame)) => {
+                                    self.closed = true;
+                                    self.close_code = frame.map(|f| f.code);
+                                }
+                                Err(_) => self.closed = true,
+                                Ok(WsMessage::Binary(bytes)) => self
+                                    .incoming
+                                    .push(self.codec.decode_server_frame(&bytes).unwrap()),
+                                Ok(WsMessage::Text(text)) => self
+                                    .incoming
+                                    .push(self.codec.decode_server_frame_json(&text).unwrap()),
+                                // tungstenite answers pings on its own
+                                Ok(WsMessage::Ping(_) | WsMessage::Pong(_)) => {}
+                                Ok(msg) => panic!("unexpected websocket message: {:?}", msg),
+                            }
+                        }
+                        true
+                    }
+                    None => {
+                        self.closed = true;
+                        false
+                    }
+                },
+                _ = tokio::time::sleep(Duration::from_millis(50)) => false)
+    }
+}
+
+fn is_broadcast(frame: &ServerFrame, exp_room: &str, exp_sender: &str, exp_msg: &str) -> bool {
+    matches!(frame, ServerFrame::Broadcast { room, sender, msg, .. }
+        if room == exp_room && sender == exp_sender && msg == exp_msg)
+}
+
+/// A client for the length-prefixed transport, which sends and receives frames one at a time.
+pub struct FramedClient<S = TcpStream> {
+    stream: S,
+    codec: Codec,
+    msg_count: RequestId,
+}
+
+impl FramedClient {
+    pub async fn connect(url: &str) -> Self {
+        Self::new(TcpStream::connect(url).await.expect("Failed to connect"))
+    }
+}
+
+impl FramedClient<UnixStream> {
+    pub async fn connect_unix(path: &Path) -> Self {
+        Self::new(UnixStream::connect(path).await.expect("Failed to connect"))
+    }
+}
+
+impl<S> FramedClient<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn new(stream: S) -> Self {
+        Self {
+            stream,
+            codec: Codec::new(),
+            msg_count: 0,
+        }
+    }
+
+    pub async fn send_frame(&mut self, frame: ClientFrameType) -> RequestId {
+        let id = self.msg_count;
+        let bytes = self
+            .codec
+            .encode_client_frame(ClientFrame { id, data: frame })
+            .unwrap();
+        self.send_raw(&bytes).await;
+        self.msg_count += 1;
+        id
+    }
+
+    /// Send a length-prefixed frame that might not be a valid frame at all.
+    pub async fn send_raw(&mut self, bytes: &[u8]) {
+        self.stream
+            .write_all(&(bytes.len() as u32).to_le_bytes())
+            .await
+            .unwrap();
+        self.stream.write_all(bytes).await.unwrap();
+    }
+
+    pub async fn recv(&mut self) -> ServerFrame {
+        let read = async {
+            let len = self.stream.read_u32_le().await.unwrap();
+            let mut bytes = vec![0; len as usize];
+            self.stream.read_exact(&mut bytes).await.unwrap();
+            bytes
+        };
+        let bytes = tokio::time::timeout(Duration::from_secs(1), read)
+            .await
+            .expect("no frame received");
+        self.codec.decode_server_frame(&bytes).unwrap()
     }
 }
@@ -1,7 +1,23 @@
 mod suite;
 
-use minichat_server::frame::ServerFrame;
-use suite::{run_ws_server, Client};
+use std::io::ErrorKind;
+use std::time::Duration;
+
+use minichat_server::config::Config;
+use minichat_server::frame::{
+    ClientFrameType, CloseReason, ErrorCode, LogoutReason, Presence, Reaction, ServerFrame,
+    DEFAULT_ROOM, PROTOCOL_VERSION,
+};
+use minichat_server::protocol::tcp::tcp_sink_stream;
+use minichat_server::{Server, Shutdown};
+use suite::{
+    run_mixed_server, run_pinging_ws_server, run_stoppable_unix_server, run_stoppable_ws_server,
+    run_tcp_server, run_tls_server, run_unix_server, run_ws_server, run_ws_server_with_config,
+    unix_socket_path, Client, FramedClient, TestCert,
+};
+use tokio::io::AsyncReadExt as _;
+use tokio::net::TcpStream;
+use tungstenite::protocol::frame::coding::CloseCode;
 
 #[tokio::test]
 async fn login_broadcast() {
@@ -9,8 +25,11 @@ async fn login_broadcast() {
     let mut bob = Client::new("bob", &url).await;
     let jolene = Client::new("jolene", &url).await;
 
-    bob.assert_frame(ServerFrame::Login("jolene".to_string()))
-        .await;
+    bob.assert_frame(ServerFrame::Login {
+        room: DEFAULT_ROOM.to_string(),
+        handle: "jolene".to_string(),
+    })
+    .await;
 
     bob.close().await;
     jolene.close().await;
@@ -24,13 +43,22 @@ async fn whos_present_on_login() {
     let mut lurker = Client::new("samantha", &url).await;
 
     jolene
-        .assert_frame(ServerFrame::Present("bob".to_string()))
+        .assert_frame(ServerFrame::Present {
+            room: DEFAULT_ROOM.to_string(),
+            handle: "bob".to_string(),
+        })
         .await;
     lurker
-        .assert_frame(ServerFrame::Present("bob".to_string()))
+        .assert_frame(ServerFrame::Present {
+            room: DEFAULT_ROOM.to_string(),
+            handle: "bob".to_string(),
+        })
         .await;
     lurker
-        .assert_frame(ServerFrame::Present("jolene".to_string()))
+        .assert_frame(ServerFrame::Present {
+            room: DEFAULT_ROOM.to_string(),
+            handle: "jolene".to_string(),
+        })
         .await;
 
     bob.close().await;
@@ -47,16 +75,28 @@ async fn logout_broadcast() {
 
     bob.close().await;
     jolene
-        .assert_frame(ServerFrame::Logout("bob".to_string()))
+        .assert_frame(ServerFrame::Logout {
+            room: DEFAULT_ROOM.to_string(),
+            handle: "bob".to_string(),
+            reason: LogoutReason::Disconnected,
+        })
         .await;
     lurker
-        .assert_frame(ServerFrame::Logout("bob".to_string()))
+        .assert_frame(ServerFrame::Logout {
+            room: DEFAULT_ROOM.to_string(),
+            handle: "bob".to_string(),
+            reason: LogoutReason::Disconnected,
+        })
         .await;
 
     jolene.close().await;
 
     lurker
-        .assert_frame(ServerFrame::Logout("jolene".to_string()))
+        .assert_frame(ServerFrame::Logout {
+            room: DEFAULT_ROOM.to_string(),
+            handle: "jolene".to_string(),
+            reason: LogoutReason::Disconnected,
+        })
         .await;
 
     lurker.close().await;
@@ -83,3 +123,1381 @@ async fn msg_broadcast() {
     bob.close().await;
     jolene.close().await;
 }
+
+#[tokio::test]
+async fn room_msgs_stay_in_room() {
+    let url = run_ws_server().await;
+    let mut bob = Client::new("bob", &url).await;
+    let mut jolene = Client::new("jolene", &url).await;
+    let mut lurker = Client::new("samantha", &url).await;
+
+    bob.join("rust").await;
+    jolene.join("rust").await;
+
+    bob.send_room_msg("rust", "borrowck says no").await;
+    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+    jolene
+        .assert_room_broadcast("rust", "bob", "borrowck says no")
+        .await;
+    lurker
+        .assert_no_room_broadcast("rust", "bob", "borrowck says no")
+        .await;
+
+    bob.close().await;
+    jolene.close().await;
+    lurker.close().await;
+}
+
+#[tokio::test]
+async fn room_presence() {
+    let url = run_ws_server().await;
+    let mut bob = Client::new("bob", &url).await;
+    let mut jolene = Client::new("jolene", &url).await;
+
+    bob.join("rust").await;
+    jolene.join("rust").await;
+
+    jolene
+        .assert_frame(ServerFrame::Present {
+            room: "rust".to_string(),
+            handle: "bob".to_string(),
+        })
+        .await;
+    bob.assert_frame(ServerFrame::Login {
+        room: "rust".to_string(),
+        handle: "jolene".to_string(),
+    })
+    .await;
+
+    jolene.leave("rust").await;
+    bob.assert_frame(ServerFrame::Logout {
+        room: "rust".to_string(),
+        handle: "jolene".to_string(),
+        reason: LogoutReason::Left,
+    })
+    .await;
+
+    bob.close().await;
+    jolene.close().await;
+}
+
+#[tokio::test]
+async fn invalid_rooms() {
+    let url = run_ws_server().await;
+    let mut bob = Client::new("bob", &url).await;
+
+    for room in ["", "two words", "bell\u{7}", &"r".repeat(33)] {
+        let id = bob
+            .send_frame(ClientFrameType::Join(room.to_string()))
+            .await;
+        bob.assert_error(id, ErrorCode::InvalidRoom).await;
+    }
+    bob.join(&"r".repeat(32)).await;
+
+    bob.close().await;
+}
+
+#[tokio::test]
+async fn msg_to_room_not_joined() {
+    let url = run_ws_server().await;
+    let mut bob = Client::new("bob", &url).await;
+
+    let id = bob.send_room_msg("rust", "anyone?").await;
+    bob.assert_error(id, ErrorCode::UnknownRoom).await;
+
+    bob.close().await;
+}
+
+#[tokio::test]
+async fn direct_msg() {
+    let url = run_ws_server().await;
+    let mut bob = Client::new("bob", &url).await;
+    let mut jolene = Client::new("jolene", &url).await;
+    let mut lurker = Client::new("samantha", &url).await;
+
+    let id = bob.send_direct_msg("jolene", "psst").await;
+    bob.assert_frame(ServerFrame::Okay(id)).await;
+
+    let frame = ServerFrame::Direct {
+        sender: "bob".to_string(),
+        msg: "psst".to_string(),
+    };
+    jolene.assert_frame(frame.clone()).await;
+    lurker.assert_no_frame(frame).await;
+
+    bob.close().await;
+    jolene.close().await;
+    lurker.close().await;
+}
+
+#[tokio::test]
+async fn direct_msg_to_absent_user() {
+    let url = run_ws_server().await;
+    let mut bob = Client::new("bob", &url).await;
+
+    let id = bob.send_direct_msg("jolene", "psst").await;
+    bob.assert_frame(ServerFrame::error(
+        id,
+        ErrorCode::NotLoggedIn,
+        "jolene is not logged in",
+    ))
+    .await;
+
+    bob.close().await;
+}
+
+#[tokio::test]
+async fn msg_ids_and_timestamps() {
+    let url = run_ws_server().await;
+    let mut bob = Client::new("bob", &url).await;
+    let mut jolene = Client::new("jolene", &url).await;
+
+    let first = bob.send_msg("one").await;
+    let first = bob.expect_receipt(first).await;
+    let second = bob.send_msg("two").await;
+    let second = bob.expect_receipt(second).await;
+    assert!(second > first);
+
+    assert_eq!(jolene.assert_broadcast("bob", "one").await, first);
+    assert_eq!(jolene.assert_broadcast("bob", "two").await, second);
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    let frame = jolene
+        .expect_frame("broadcast", |f| matches!(f, ServerFrame::Broadcast { .. }))
+        .await;
+    let ServerFrame::Broadcast { timestamp, .. } = frame else {
+        unreachable!()
+    };
+    assert!(timestamp <= now && now - timestamp < 10_000);
+
+    bob.close().await;
+    jolene.close().await;
+}
+
+#[tokio::test]
+async fn history_replay_on_login() {
+    let url = run_ws_server_with_config(Config {
+        history_replay: 2,
+        ..Config::default()
+    })
+    .await;
+    let mut bob = Client::new("bob", &url).await;
+
+    for msg in ["one", "two", "three"] {
+        let id = bob.send_msg(msg).await;
+        bob.expect_receipt(id).await;
+    }
+
+    let mut jolene = Client::new("jolene", &url).await;
+    jolene.assert_broadcast("bob", "two").await;
+    jolene.assert_broadcast("bob", "three").await;
+    jolene
+        .assert_no_room_broadcast(DEFAULT_ROOM, "bob", "one")
+        .await;
+
+    bob.close().await;
+    jolene.close().await;
+}
+
+#[tokio::test]
+async fn history_persists_across_restarts() {
+    let path = std::env::temp_dir().join(format!("minichat-history-{}.log", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let config = Config {
+        history_file: Some(path.clone()),
+        ..Config::default()
+    };
+
+    let url = run_ws_server_with_config(config.clone()).await;
+    let mut bob = Client::new("bob", &url).await;
+    let id = bob.send_msg("remember me").await;
+    let msg_id = bob.expect_receipt(id).await;
+    bob.close().await;
+
+    let url = run_ws_server_with_config(config).await;
+    let mut jolene = Client::new("jolene", &url).await;
+    assert_eq!(jolene.assert_broadcast("bob", "remember me").await, msg_id);
+
+    let id = jolene.send_msg("i do").await;
+    assert!(jolene.expect_receipt(id).await > msg_id);
+    jolene.close().await;
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn history_pagination() {
+    let url = run_ws_server_with_config(Config {
+        history_page_limit: 3,
+        ..Config::default()
+    })
+    .await;
+    let mut bob = Client::new("bob", &url).await;
+
+    let mut ids = Vec::new();
+    for msg in ["one", "two", "three", "four", "five"] {
+        let id = bob.send_msg(msg).await;
+        ids.push(bob.expect_receipt(id).await);
+    }
+
+    let page = bob.fetch_history(DEFAULT_ROOM, Some(ids[3]), 2).await;
+    assert_eq!(
+        page,
+        [
+            ("bob".to_string(), "two".to_string()),
+            ("bob".to_string(), "three".to_string())
+        ]
+    );
+
+    // capped by the server
+    let page = bob.fetch_history(DEFAULT_ROOM, None, 100).await;
+    assert_eq!(page.len(), 3);
+
+    let page = bob.fetch_history(DEFAULT_ROOM, Some(ids[0]), 100).await;
+    assert!(page.is_empty());
+
+    bob.close().await;
+}
+
+#[tokio::test]
+async fn hello() {
+    let url = run_ws_server().await;
+    let mut bob = Client::connect(&url).await;
+
+    let ServerFrame::Welcome {
+        protocol_version,
+        features,
+        limits,
+        ..
+    } = bob.hello(PROTOCOL_VERSION).await
+    else {
+        panic!("expected a welcome frame");
+    };
+    assert_eq!(protocol_version, PROTOCOL_VERSION);
+    assert!(features.iter().any(|f| f == "rooms"));
+    assert_eq!(limits.max_msg_len, Config::default().max_msg_len as u32);
+
+    bob.login("bob").await;
+    bob.close().await;
+}
+
+#[tokio::test]
+async fn hello_from_the_future() {
+    let url = run_ws_server().await;
+    let mut bob = Client::connect(&url).await;
+
+    let ServerFrame::Welcome {
+        protocol_version, ..
+    } = bob.hello(PROTOCOL_VERSION + 1).await
+    else {
+        panic!("expected a welcome frame");
+    };
+    assert_eq!(protocol_version, PROTOCOL_VERSION);
+
+    bob.close().await;
+}
+
+#[tokio::test]
+async fn incompatible_hello() {
+    let url = run_ws_server().await;
+    let mut bob = Client::connect(&url).await;
+
+    let response = bob.hello(0).await;
+    assert!(matches!(response, ServerFrame::Err(_, _)));
+    bob.assert_closed().await;
+}
+
+/// Clients that don't say hello speak protocol version 1, byte for byte.
+#[tokio::test]
+async fn login_without_hello() {
+    let url = run_ws_server().await;
+    let mut jolene = Client::new("jolene", &url).await;
+    let mut bob = Client::connect(&url).await;
+
+    // Login("bob")
+    bob.send_raw(&[0, 0, 3, 0, 0, 0, 98, 111, 98]).await;
+    bob.assert_frame(ServerFrame::Okay(0)).await;
+    bob.assert_frame(ServerFrame::PresentV1("jolene".to_string()))
+        .await;
+
+    // Msg("hi")
+    bob.send_raw(&[1, 1, 2, 0, 0, 0, 104, 105]).await;
+    bob.assert_frame(ServerFrame::Okay(1)).await;
+    jolene.assert_broadcast("bob", "hi").await;
+
+    bob.close().await;
+    jolene.close().await;
+}
+
+#[tokio::test]
+async fn msg_too_long() {
+    let url = run_ws_server_with_config(Config {
+        max_msg_len: 5,
+        ..Config::default()
+    })
+    .await;
+    let mut bob = Client::new("bob", &url).await;
+
+    let id = bob.send_msg("too long").await;
+    bob.assert_error(id, ErrorCode::MsgTooLong).await;
+
+    bob.close().await;
+}
+
+#[tokio::test]
+async fn wide_request_ids() {
+    let url = run_ws_server().await;
+    let mut bob = Client::new("bob", &url).await;
+
+    bob.set_next_request_id(70_000);
+    let id = bob.send_msg("hi").await;
+    assert_eq!(id, 70_000);
+    bob.expect_receipt(id).await;
+
+    bob.close().await;
+}
+
+#[tokio::test]
+async fn protocol_v1_client() {
+    let url = run_ws_server().await;
+    let mut bob = Client::connect(&url).await;
+    let mut jolene = Client::new("jolene", &url).await;
+
+    let ServerFrame::Welcome {
+        protocol_version, ..
+    } = bob.hello(1).await
+    else {
+        panic!("expected a welcome frame");
+    };
+    assert_eq!(protocol_version, 1);
+
+    bob.login("bob").await;
+    bob.assert_frame(ServerFrame::PresentV1("jolene".to_string()))
+        .await;
+    let id = bob
+        .send_frame(ClientFrameType::MsgV1("hi from the past".to_string()))
+        .await;
+    bob.assert_frame(ServerFrame::Okay(id)).await;
+    jolene.assert_broadcast("bob", "hi from the past").await;
+
+    jolene.send_msg("hi back").await;
+    bob.assert_frame(ServerFrame::BroadcastV1 {
+        sender: "jolene".to_string(),
+        msg: "hi back".to_string(),
+    })
+    .await;
+
+    // no error codes in version 1
+    let id = bob.send_room_msg("rust", "anyone?").await;
+    bob.assert_frame(ServerFrame::Err(id, "unknown room".to_string()))
+        .await;
+
+    // and nothing version 1 clients wouldn't be able to decode
+    jolene.set_status(Presence::Busy, Some("coding")).await;
+    jolene
+        .send_frame(ClientFrameType::Typing {
+            room: DEFAULT_ROOM.to_string(),
+        })
+        .await;
+    // nor anything from other rooms, which would look like it came from the default one
+    jolene.join("rust").await;
+    bob.join("rust").await;
+    jolene.send_room_msg("rust", "psst").await;
+    bob.assert_no_frame(ServerFrame::BroadcastV1 {
+        sender: "jolene".to_string(),
+        msg: "psst".to_string(),
+    })
+    .await;
+    bob.assert_no_frame_matching("frame that isn't part of version 1", |f| {
+        !matches!(
+            f,
+            ServerFrame::Okay(_)
+                | ServerFrame::Err(..)
+                | ServerFrame::BroadcastV1 { .. }
+                | ServerFrame::PresentV1(_)
+                | ServerFrame::LoginV1(_)
+                | ServerFrame::LogoutV1(_)
+                | ServerFrame::Welcome { .. }
+        )
+    })
+    .await;
+
+    bob.close().await;
+    jolene.close().await;
+}
+
+#[tokio::test]
+async fn msg_to_room_left() {
+    let url = run_ws_server().await;
+    let mut bob = Client::new("bob", &url).await;
+
+    bob.join("rust").await;
+    bob.leave("rust").await;
+
+    let id = bob.send_room_msg(DEFAULT_ROOM, "hi").await;
+    bob.expect_receipt(id).await;
+
+    let id = bob.send_room_msg("rust", "anyone?").await;
+    bob.assert_error(id, ErrorCode::UnknownRoom).await;
+
+    bob.close().await;
+}
+
+#[tokio::test]
+async fn handle_taken() {
+    let url = run_ws_server().await;
+    let bob = Client::new("bob", &url).await;
+    let mut impostor = Client::connect(&url).await;
+    impostor.hello(PROTOCOL_VERSION).await;
+
+    let id = impostor.send_login("bob").await;
+    impostor.assert_error(id, ErrorCode::HandleTaken).await;
+
+    bob.close().await;
+}
+
+#[tokio::test]
+async fn invalid_handle() {
+    let url = run_ws_server().await;
+
+    for handle in ["", "bob ross", &"b".repeat(33)] {
+        let mut client = Client::connect(&url).await;
+        client.hello(PROTOCOL_VERSION).await;
+
+        let id = client.send_login(handle).await;
+        client.assert_error(id, ErrorCode::InvalidHandle).await;
+    }
+}
+
+#[tokio::test]
+async fn invalid_frames_are_reported() {
+    let url = run_ws_server().await;
+    let mut bob = Client::new("bob", &url).await;
+
+    bob.send_raw(&[0xff, 1, 2]).await;
+    bob.expect_frame("protocol error", |f| {
+        matches!(f, ServerFrame::ProtocolError { code, .. } if *code == ErrorCode::InvalidFrame)
+    })
+    .await;
+
+    // the connection is still usable
+    let id = bob.send_msg("still here").await;
+    bob.expect_receipt(id).await;
+
+    bob.close().await;
+}
+
+#[tokio::test]
+async fn unexpected_frames_are_reported() {
+    let url = run_ws_server().await;
+    let mut bob = Client::connect(&url).await;
+    bob.hello(PROTOCOL_VERSION).await;
+
+    let id = bob.send_msg("too early").await;
+    bob.assert_error(id, ErrorCode::UnexpectedFrame).await;
+
+    bob.login("bob").await;
+
+    let id = bob.send_login("bob").await;
+    bob.assert_error(id, ErrorCode::UnexpectedFrame).await;
+
+    let id = bob
+        .send_frame(ClientFrameType::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: Vec::new(),
+        })
+        .await;
+    bob.assert_error(id, ErrorCode::UnexpectedFrame).await;
+
+    bob.close().await;
+}
+
+#[tokio::test]
+async fn too_many_protocol_errors() {
+    let url = run_ws_server_with_config(Config {
+        max_protocol_errors: 2,
+        ..Config::default()
+    })
+    .await;
+    let mut bob = Client::new("bob", &url).await;
+
+    bob.send_raw(&[0xff]).await;
+    bob.send_raw(&[0xff]).await;
+
+    bob.assert_frame(ServerFrame::Close {
+        reason: CloseReason::TooManyErrors,
+        msg: "too many protocol errors".to_string(),
+    })
+    .await;
+    bob.assert_closed_with(CloseCode::Policy).await;
+}
+
+#[tokio::test]
+async fn ping() {
+    let url = run_ws_server().await;
+    let mut bob = Client::connect(&url).await;
+    bob.hello(PROTOCOL_VERSION).await;
+
+    let id = bob.send_frame(ClientFrameType::Ping).await;
+    bob.assert_frame(ServerFrame::Pong(id)).await;
+
+    bob.login("bob").await;
+    let id = bob.send_frame(ClientFrameType::Ping).await;
+    bob.assert_frame(ServerFrame::Pong(id)).await;
+
+    bob.close().await;
+}
+
+#[tokio::test]
+async fn idle_timeout() {
+    let url = run_ws_server_with_config(Config {
+        idle_timeout: Duration::from_millis(300),
+        ..Config::default()
+    })
+    .await;
+    let mut bob = Client::new("bob", &url).await;
+    let mut jolene = Client::new("jolene", &url).await;
+
+    // pinging keeps jolene around, while bob stays quiet
+    for _ in 0..4 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let id = jolene.send_frame(ClientFrameType::Ping).await;
+        jolene.assert_frame(ServerFrame::Pong(id)).await;
+    }
+
+    bob.assert_frame(ServerFrame::Close {
+        reason: CloseReason::IdleTimeout,
+        msg: "nothing received for too long".to_string(),
+    })
+    .await;
+    bob.assert_closed().await;
+    jolene
+        .assert_frame(ServerFrame::Logout {
+            room: DEFAULT_ROOM.to_string(),
+            handle: "bob".to_string(),
+            reason: LogoutReason::Timeout,
+        })
+        .await;
+
+    // the handle is free again
+    let bob = Client::new("bob", &url).await;
+    bob.close().await;
+    jolene.close().await;
+}
+
+#[tokio::test]
+async fn stalled_handshakes_dont_block_others() {
+    let url = run_ws_server_with_config(Config {
+        handshake_timeout: Duration::from_millis(300),
+        ..Config::default()
+    })
+    .await;
+    let mut stalled = TcpStream::connect(&url).await.unwrap();
+
+    let bob = Client::new("bob", &url).await;
+    bob.close().await;
+
+    let mut buf = [0; 16];
+    let read = tokio::time::timeout(Duration::from_secs(1), stalled.read(&mut buf)).await;
+    assert!(
+        matches!(read, Ok(Ok(0) | Err(_))),
+        "stalled connection not closed"
+    );
+}
+
+#[tokio::test]
+async fn login_timeout() {
+    let url = run_ws_server_with_config(Config {
+        login_timeout: Duration::from_millis(300),
+        ..Config::default()
+    })
+    .await;
+    let mut bob = Client::connect(&url).await;
+    bob.hello(PROTOCOL_VERSION).await;
+
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    bob.assert_frame(ServerFrame::Close {
+        reason: CloseReason::LoginTimeout,
+        msg: "didn't log in in time".to_string(),
+    })
+    .await;
+    bob.assert_closed().await;
+}
+
+#[tokio::test]
+async fn shutdown() {
+    let (url, shutdown) = run_stoppable_ws_server(Config::default()).await;
+    let mut bob = Client::new("bob", &url).await;
+    let mut anon = Client::connect(&url).await;
+    anon.hello(PROTOCOL_VERSION).await;
+
+    shutdown
+        .send(Shutdown {
+            reason: "maintenance".to_string(),
+            reconnect_after: Some(Duration::from_secs(30)),
+        })
+        .unwrap();
+
+    let frame = ServerFrame::ServerShutdown {
+        reason: "maintenance".to_string(),
+        reconnect_after: Some(30),
+    };
+    bob.assert_frame(frame.clone()).await;
+    bob.assert_closed_with(CloseCode::Away).await;
+    anon.assert_frame(frame).await;
+    anon.assert_closed_with(CloseCode::Away).await;
+
+    assert!(TcpStream::connect(&url).await.is_err());
+}
+
+#[tokio::test]
+async fn raw_tcp() {
+    let url = run_tcp_server().await;
+    let mut bob = FramedClient::connect(&url).await;
+
+    bob.send_frame(ClientFrameType::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        capabilities: Vec::new(),
+    })
+    .await;
+    assert!(matches!(bob.recv().await, ServerFrame::Welcome { .. }));
+
+    let id = bob
+        .send_frame(ClientFrameType::Login("bob".to_string()))
+        .await;
+    assert_eq!(bob.recv().await, ServerFrame::Okay(id));
+    assert_eq!(
+        bob.recv().await,
+        ServerFrame::Present {
+            room: DEFAULT_ROOM.to_string(),
+            handle: "bob".to_string(),
+        }
+    );
+
+    // too long frames are skipped without losing track of the ones after them
+    bob.send_raw(&vec![0; 2 << 20]).await;
+    assert_eq!(
+        bob.recv().await,
+        ServerFrame::ProtocolError {
+            code: ErrorCode::InvalidFrame,
+            msg: "frame too long".to_string(),
+        }
+    );
+
+    let id = bob
+        .send_frame(ClientFrameType::Msg {
+            room: DEFAULT_ROOM.to_string(),
+            msg: "hi".to_string(),
+        })
+        .await;
+    assert!(matches!(bob.recv().await, ServerFrame::Receipt { id: r_id, .. } if r_id == id));
+}
+
+#[tokio::test]
+async fn unix_socket() {
+    let path = run_unix_server().await;
+    let mut bob = FramedClient::connect_unix(&path).await;
+
+    let id = bob
+        .send_frame(ClientFrameType::Login("bob".to_string()))
+        .await;
+    assert_eq!(bob.recv().await, ServerFrame::Okay(id));
+    // no hello, so this is protocol version 1
+    assert_eq!(bob.recv().await, ServerFrame::PresentV1("bob".to_string()));
+}
+
+#[tokio::test]
+async fn unix_socket_files() {
+    let path = unix_socket_path();
+    // a socket left behind by a server that's gone is replaced
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+    let stop = run_stoppable_unix_server(path.clone()).await;
+    let _bob = FramedClient::connect_unix(&path).await;
+
+    // but not one that's still in use
+    let err = Server::new(Config::default())
+        .unwrap()
+        .listen_unix(path.clone(), tcp_sink_stream)
+        .serve()
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::AddrInUse);
+    let _jolene = FramedClient::connect_unix(&path).await;
+
+    // and it's removed on shutdown
+    stop.send(Shutdown {
+        reason: "maintenance".to_string(),
+        reconnect_after: None,
+    })
+    .unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!path.exists());
+}
+
+#[tokio::test]
+async fn tls() {
+    let cert = TestCert::new();
+    let old = cert.renew();
+    let url = run_tls_server(&cert).await;
+
+    let mut bob = Client::connect_tls(&url, &old).await.unwrap();
+    bob.hello(PROTOCOL_VERSION).await;
+    bob.login("bob").await;
+
+    // certificates are read again on SIGHUP, without dropping anyone
+    let new = cert.renew();
+    let status = std::process::Command::new("kill")
+        .args(["-HUP", &std::process::id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    assert!(Client::connect_tls(&url, &old).await.is_err());
+    let mut jolene = Client::connect_tls(&url, &new).await.unwrap();
+    jolene.hello(PROTOCOL_VERSION).await;
+    jolene.login("jolene").await;
+
+    bob.assert_frame(ServerFrame::Login {
+        room: DEFAULT_ROOM.to_string(),
+        handle: "jolene".to_string(),
+    })
+    .await;
+
+    bob.close().await;
+    jolene.close().await;
+}
+
+#[tokio::test]
+async fn json() {
+    let url = run_ws_server().await;
+    let mut bob = Client::connect_json(&url).await;
+    bob.hello(PROTOCOL_VERSION).await;
+    bob.login("bob").await;
+    let mut jolene = Client::new("jolene", &url).await;
+
+    bob.send_msg("hi").await;
+    jolene.assert_broadcast("bob", "hi").await;
+    jolene.send_msg("hello").await;
+    bob.assert_broadcast("jolene", "hello").await;
+
+    // once a connection speaks JSON, it sticks to it
+    bob.send_raw(&[0xff]).await;
+    bob.assert_frame(ServerFrame::ProtocolError {
+        code: ErrorCode::InvalidFrame,
+        msg: "a connection can't mix binary and JSON frames".to_string(),
+    })
+    .await;
+
+    bob.close().await;
+    jolene.close().await;
+}
+
+#[tokio::test]
+async fn json_without_hello() {
+    let url = run_ws_server().await;
+    let mut jolene = Client::new("jolene", &url).await;
+    // there are no JSON clients from before hello, so this isn't protocol version 1
+    let mut bob = Client::connect_json(&url).await;
+    bob.login("bob").await;
+    bob.assert_frame(ServerFrame::Present {
+        room: DEFAULT_ROOM.to_string(),
+        handle: "jolene".to_string(),
+    })
+    .await;
+
+    let id = bob.send_msg("hi").await;
+    bob.expect_receipt(id).await;
+    jolene.assert_broadcast("bob", "hi").await;
+
+    bob.close().await;
+    jolene.close().await;
+}
+
+#[tokio::test]
+async fn mixed_transports() {
+    let (ws_url, tcp_url) = run_mixed_server().await;
+    let mut bob = Client::new("bob", &ws_url).await;
+
+    let mut jolene = FramedClient::connect(&tcp_url).await;
+    jolene
+        .send_frame(ClientFrameType::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: Vec::new(),
+        })
+        .await;
+    jolene.recv().await;
+    let id = jolene
+        .send_frame(ClientFrameType::Login("jolene".to_string()))
+        .await;
+    assert_eq!(jolene.recv().await, ServerFrame::Okay(id));
+
+    bob.assert_frame(ServerFrame::Login {
+        room: DEFAULT_ROOM.to_string(),
+        handle: "jolene".to_string(),
+    })
+    .await;
+
+    bob.send_msg("hi").await;
+    loop {
+        if let ServerFrame::Broadcast { sender, msg, .. } = jolene.recv().await {
+            assert_eq!((sender.as_str(), msg.as_str()), ("bob", "hi"));
+            break;
+        }
+    }
+
+    bob.close().await;
+}
+
+#[tokio::test]
+async fn edit_and_delete() {
+    let url = run_ws_server().await;
+    let mut bob = Client::new("bob", &url).await;
+    let mut jolene = Client::new("jolene", &url).await;
+
+    let id = bob.send_msg("helo").await;
+    let first = bob.expect_receipt(id).await;
+    let id = bob.send_msg("oops").await;
+    let second = bob.expect_receipt(id).await;
+
+    let id = bob.edit(first, "hello").await;
+    bob.assert_frame(ServerFrame::Okay(id)).await;
+    jolene
+        .assert_frame(ServerFrame::Edited {
+            room: DEFAULT_ROOM.to_string(),
+            msg_id: first,
+            msg: "hello".to_string(),
+        })
+        .await;
+
+    let id = bob.delete(second).await;
+    bob.assert_frame(ServerFrame::Okay(id)).await;
+    jolene
+        .assert_frame(ServerFrame::Deleted {
+            room: DEFAULT_ROOM.to_string(),
+            msg_id: second,
+        })
+        .await;
+
+    let page = jolene.fetch_history(DEFAULT_ROOM, None, 10).await;
+    assert_eq!(page, [("bob".to_string(), "hello".to_string())]);
+
+    let id = bob.edit(second, "gone").await;
+    bob.assert_error(id, ErrorCode::UnknownMsg).await;
+
+    bob.close().await;
+    jolene.close().await;
+}
+
+#[tokio::test]
+async fn only_own_msgs_can_be_changed() {
+    let url = run_ws_server().await;
+    let mut bob = Client::new("bob", &url).await;
+    let mut jolene = Client::new("jolene", &url).await;
+
+    let id = bob.send_msg("mine").await;
+    let msg_id = bob.expect_receipt(id).await;
+
+    let id = jolene.edit(msg_id, "mine now").await;
+    jolene.assert_error(id, ErrorCode::Forbidden).await;
+    let id = jolene.delete(msg_id).await;
+    jolene.assert_error(id, ErrorCode::Forbidden).await;
+
+    bob.assert_no_frame_matching("edits", |f| {
+        matches!(f, ServerFrame::Edited { .. } | ServerFrame::Deleted { .. })
+    })
+    .await;
+
+    bob.close().await;
+    jolene.close().await;
+}
+
+#[tokio::test]
+async fn reactions() {
+    let url = run_ws_server().await;
+    let mut bob = Client::new("bob", &url).await;
+    let mut jolene = Client::new("jolene", &url).await;
+
+    let id = bob.send_msg("lunch?").await;
+    let msg_id = bob.expect_receipt(id).await;
+
+    let thumbs_up = |count| ServerFrame::Reactions {
+        room: DEFAULT_ROOM.to_string(),
+        msg_id,
+        reactions: vec![Reaction {
+            emoji: "👍".to_string(),
+            count,
+        }],
+    };
+
+    let id = jolene.react(msg_id, "👍", true).await;
+    jolene.assert_frame(ServerFrame::Okay(id)).await;
+    jolene.assert_frame(thumbs_up(1)).await;
+    bob.assert_frame(thumbs_up(1)).await;
+
+    let id = bob.react(msg_id, "👍", true).await;
+    bob.assert_frame(ServerFrame::Okay(id)).await;
+    jolene.assert_frame(thumbs_up(2)).await;
+
+    let id = bob.react(msg_id, "👍", false).await;
+    bob.assert_frame(ServerFrame::Okay(id)).await;
+    jolene.assert_frame(thumbs_up(1)).await;
+
+    // latecomers get to see reactions to messages from history
+    let mut late = Client::new("late", &url).await;
+    late.assert_frame(thumbs_up(1)).await;
+
+    let id = jolene.react(msg_id, "not an emoji", true).await;
+    jolene.assert_error(id, ErrorCode::InvalidReaction).await;
+    let id = jolene.react(msg_id + 1, "👍", true).await;
+    jolene.assert_error(id, ErrorCode::UnknownMsg).await;
+
+    bob.close().await;
+    jolene.close().await;
+    late.close().await;
+}
+
+#[tokio::test]
+async fn typing() {
+    let url = run_ws_server_with_config(Config {
+        typing_timeout: Duration::from_millis(300),
+        ..Config::default()
+    })
+    .await;
+    let mut bob = Client::new("bob", &url).await;
+    let mut jolene = Client::new("jolene", &url).await;
+
+    let typing = |typing| ServerFrame::Typing {
+        room: DEFAULT_ROOM.to_string(),
+        handle: "bob".to_string(),
+        typing,
+    };
+
+    let id = bob
+        .send_frame(ClientFrameType::Typing {
+            room: DEFAULT_ROOM.to_string(),
+        })
+        .await;
+    jolene.assert_frame(typing(true)).await;
+    bob.assert_no_frame(ServerFrame::Okay(id)).await;
+    jolene.forget_incoming().await;
+
+    // still typing, nothing new to tell
+    bob.send_frame(ClientFrameType::Typing {
+        room: DEFAULT_ROOM.to_string(),
+    })
+    .await;
+    jolene
+        .assert_no_frame_matching("typing", |f| matches!(f, ServerFrame::Typing { .. }))
+        .await;
+
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    jolene.assert_frame(typing(false)).await;
+
+    // posting a message is the end of typing, no expiry needed
+    bob.send_frame(ClientFrameType::Typing {
+        room: DEFAULT_ROOM.to_string(),
+    })
+    .await;
+    jolene.assert_frame(typing(true)).await;
+    bob.send_msg("done").await;
+    jolene.assert_broadcast("bob", "done").await;
+    jolene.forget_incoming().await;
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    jolene.assert_no_frame(typing(false)).await;
+
+    bob.close().await;
+    jolene.close().await;
+}
+
+#[tokio::test]
+async fn read_markers() {
+    let url = run_ws_server().await;
+    let mut bob = Client::new("bob", &url).await;
+    let mut jolene = Client::new("jolene", &url).await;
+
+    let mut ids = Vec::new();
+    for msg in ["one", "two"] {
+        let id = bob.send_msg(msg).await;
+        ids.push(bob.expect_receipt(id).await);
+    }
+
+    let id = jolene.send_frame(ClientFrameType::MarkRead(ids[1])).await;
+    jolene.assert_frame(ServerFrame::Okay(id)).await;
+    let read_up_to = ServerFrame::ReadUpTo {
+        room: DEFAULT_ROOM.to_string(),
+        handle: "jolene".to_string(),
+        msg_id: ids[1],
+    };
+    bob.assert_frame(read_up_to.clone()).await;
+    bob.forget_incoming().await;
+
+    // markers don't move back
+    let id = jolene.send_frame(ClientFrameType::MarkRead(ids[0])).await;
+    jolene.assert_frame(ServerFrame::Okay(id)).await;
+    bob.assert_no_frame_matching("read marker", |f| matches!(f, ServerFrame::ReadUpTo { .. }))
+        .await;
+
+    // those joining later see who read what
+    let mut late = Client::new("late", &url).await;
+    late.assert_frame(read_up_to).await;
+
+    bob.close().await;
+    jolene.close().await;
+    late.close().await;
+}
+
+#[tokio::test]
+async fn status() {
+    let url = run_ws_server().await;
+    let mut bob = Client::new("bob", &url).await;
+    let mut jolene = Client::new("jolene", &url).await;
+
+    let busy = ServerFrame::StatusChanged {
+        handle: "bob".to_string(),
+        presence: Presence::Busy,
+        status: Some("in a meeting".to_string()),
+    };
+    bob.set_status(Presence::Busy, Some("in a meeting")).await;
+    bob.assert_frame(busy.clone()).await;
+    jolene.assert_frame(busy.clone()).await;
+
+    // latecomers see it too
+    let mut late = Client::new("late", &url).await;
+    late.assert_frame(busy.clone()).await;
+
+    // and so do the members of rooms bob joins
+    jolene.join("rust").await;
+    jolene.forget_incoming().await;
+    bob.join("rust").await;
+    jolene.assert_frame(busy).await;
+
+    let id = bob
+        .send_frame(ClientFrameType::SetStatus {
+            presence: Presence::Online,
+            status: Some("x".repeat(129)),
+        })
+        .await;
+    bob.assert_error(id, ErrorCode::InvalidStatus).await;
+
+    bob.close().await;
+    jolene.close().await;
+    late.close().await;
+}
+
+#[tokio::test]
+async fn invisible() {
+    let url = run_ws_server().await;
+    let mut bob = Client::new("bob", &url).await;
+    let mut jolene = Client::new("jolene", &url).await;
+
+    bob.set_status(Presence::Invisible, None).await;
+    jolene
+        .assert_frame(ServerFrame::Logout {
+            room: DEFAULT_ROOM.to_string(),
+            handle: "bob".to_string(),
+            reason: LogoutReason::Left,
+        })
+        .await;
+
+    let mut late = Client::new("late", &url).await;
+    late.assert_frame(ServerFrame::Present {
+        room: DEFAULT_ROOM.to_string(),
+        handle: "jolene".to_string(),
+    })
+    .await;
+    late.assert_no_frame(ServerFrame::Present {
+        room: DEFAULT_ROOM.to_string(),
+        handle: "bob".to_string(),
+    })
+    .await;
+
+    // direct messages are turned away like for users who aren't logged in
+    let id = jolene.send_direct_msg("bob", "psst").await;
+    jolene.assert_error(id, ErrorCode::NotLoggedIn).await;
+    bob.assert_no_frame(ServerFrame::Direct {
+        sender: "jolene".to_string(),
+        msg: "psst".to_string(),
+    })
+    .await;
+
+    bob.set_status(Presence::Online, None).await;
+    late.assert_frame(ServerFrame::Login {
+        room: DEFAULT_ROOM.to_string(),
+        handle: "bob".to_string(),
+    })
+    .await;
+    let id = jolene.send_direct_msg("bob", "psst").await;
+    jolene.assert_frame(ServerFrame::Okay(id)).await;
+    bob.assert_frame(ServerFrame::Direct {
+        sender: "jolene".to_string(),
+        msg: "psst".to_string(),
+    })
+    .await;
+
+    bob.close().await;
+    jolene.close().await;
+    late.close().await;
+}
+
+#[tokio::test]
+async fn websocket_pings_keep_connections_alive() {
+    let config = Config {
+        idle_timeout: Duration::from_millis(300),
+        ..Config::default()
+    };
+    let url = run_pinging_ws_server(config, Duration::from_millis(100)).await;
+    // clients that don't know about ping frames still answer WebSocket pings
+    let mut bob = Client::connect(&url).await;
+    bob.login("bob").await;
+
+    bob.keep_reading(Duration::from_millis(600)).await;
+    let id = bob
+        .send_frame(ClientFrameType::MsgV1("still here".to_string()))
+        .await;
+    bob.assert_frame(ServerFrame::Okay(id)).await;
+
+    bob.close().await;
+}
+
+#[tokio::test]
+async fn away_when_idle() {
+    let url = run_ws_server_with_config(Config {
+        away_after: Duration::from_millis(300),
+        ..Config::default()
+    })
+    .await;
+    let mut bob = Client::new("bob", &url).await;
+    let mut jolene = Client::new("jolene", &url).await;
+
+    let status = |presence| ServerFrame::StatusChanged {
+        handle: "bob".to_string(),
+        presence,
+        status: None,
+    };
+
+    // pinging isn't activity
+    for _ in 0..4 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let id = bob.send_frame(ClientFrameType::Ping).await;
+        bob.assert_frame(ServerFrame::Pong(id)).await;
+    }
+    jolene.assert_frame(status(Presence::Away)).await;
+
+    let id = bob.send_msg("back").await;
+    bob.expect_receipt(id).await;
+    jolene.assert_frame(status(Presence::Online)).await;
+
+    bob.close().await;
+    jolene.close().await;
+}
+
+#[tokio::test]
+async fn rename() {
+    let url = run_ws_server().await;
+    let mut bob = Client::new("bob", &url).await;
+    let mut jolene = Client::new("jolene", &url).await;
+
+    let id = bob
+        .send_frame(ClientFrameType::Rename("jolene".to_string()))
+        .await;
+    bob.assert_error(id, ErrorCode::HandleTaken).await;
+    let id = bob
+        .send_frame(ClientFrameType::Rename("bob ross".to_string()))
+        .await;
+    bob.assert_error(id, ErrorCode::InvalidHandle).await;
+    let id = bob
+        .send_frame(ClientFrameType::Rename("bob".to_string()))
+        .await;
+    bob.assert_error(id, ErrorCode::InvalidHandle).await;
+
+    let id = bob.send_msg("lunch?").await;
+    let msg_id = bob.expect_receipt(id).await;
+    let id = bob.react(msg_id, "👍", true).await;
+    bob.assert_frame(ServerFrame::Okay(id)).await;
+    bob.send_frame(ClientFrameType::Typing {
+        room: DEFAULT_ROOM.to_string(),
+    })
+    .await;
+    let typing = |handle: &str, typing| ServerFrame::Typing {
+        room: DEFAULT_ROOM.to_string(),
+        handle: handle.to_string(),
+        typing,
+    };
+    jolene.assert_frame(typing("bob", true)).await;
+
+    let id = bob
+        .send_frame(ClientFrameType::Rename("robert".to_string()))
+        .await;
+    bob.assert_frame(ServerFrame::Okay(id)).await;
+    let renamed = ServerFrame::Renamed {
+        old: "bob".to_string(),
+        new: "robert".to_string(),
+    };
+    bob.assert_frame(renamed.clone()).await;
+    jolene.assert_frame(renamed).await;
+    jolene.assert_frame(typing("bob", false)).await;
+    jolene.assert_frame(typing("robert", true)).await;
+
+    // the reaction moved along with the handle
+    let id = bob.react(msg_id, "👍", false).await;
+    bob.assert_frame(ServerFrame::Okay(id)).await;
+    jolene
+        .assert_frame(ServerFrame::Reactions {
+            room: DEFAULT_ROOM.to_string(),
+            msg_id,
+            reactions: vec![],
+        })
+        .await;
+    jolene
+        .assert_no_frame(ServerFrame::Logout {
+            room: DEFAULT_ROOM.to_string(),
+            handle: "bob".to_string(),
+            reason: LogoutReason::Left,
+        })
+        .await;
+
+    bob.send_msg("same old me").await;
+    jolene.assert_broadcast("robert", "same old me").await;
+
+    let id = jolene.send_direct_msg("robert", "hi robert").await;
+    jolene.assert_frame(ServerFrame::Okay(id)).await;
+
+    // the old handle is free to take
+    let bob_again = Client::new("bob", &url).await;
+
+    bob.close().await;
+    jolene.close().await;
+    bob_again.close().await;
+}
+
+#[tokio::test]
+async fn threads() {
+    let url = run_ws_server().await;
+    let mut bob = Client::new("bob", &url).await;
+    let mut jolene = Client::new("jolene", &url).await;
+
+    let id = bob.send_msg("pizza or sushi?").await;
+    let root = bob.expect_receipt(id).await;
+
+    let id = jolene.send_reply(root, "pizza").await;
+    let reply = jolene.expect_receipt(id).await;
+    bob.expect_frame("reply", |f| {
+        matches!(f, ServerFrame::Broadcast { msg, reply_to, .. }
+            if msg == "pizza" && *reply_to == Some(root))
+    })
+    .await;
+
+    // replies to replies end up in the same thread
+    let id = bob.send_reply(reply, "pizza it is").await;
+    bob.expect_receipt(id).await;
+    jolene
+        .expect_frame("reply", |f| {
+            matches!(f, ServerFrame::Broadcast { msg, reply_to, .. }
+                if msg == "pizza it is" && *reply_to == Some(root))
+        })
+        .await;
+
+    assert_eq!(
+        jolene.fetch_thread(root).await,
+        [
+            ("jolene".to_string(), "pizza".to_string()),
+            ("bob".to_string(), "pizza it is".to_string())
+        ]
+    );
+
+    // history says which messages are replies
+    let id = jolene
+        .send_frame(ClientFrameType::History {
+            room: DEFAULT_ROOM.to_string(),
+            before: None,
+            limit: 10,
+        })
+        .await;
+    let page = jolene
+        .expect_frame(
+            "history page",
+            |f| matches!(f, ServerFrame::HistoryPage { id: p_id, .. } if *p_id == id),
+        )
+        .await;
+    let ServerFrame::HistoryPage { msgs, .. } = page else {
+        unreachable!();
+    };
+    assert_eq!(
+        msgs.iter().map(|m| m.reply_to).collect::<Vec<_>>(),
+        [None, Some(root), Some(root)]
+    );
+
+    let id = bob.send_reply(root + 100, "hm?").await;
+    bob.assert_error(id, ErrorCode::UnknownMsg).await;
+
+    bob.close().await;
+    jolene.close().await;
+}
+
+#[tokio::test]
+async fn mentions() {
+    let url = run_ws_server().await;
+    let mut bob = Client::new("bob", &url).await;
+    let mut jolene = Client::new("jolene", &url).await;
+    let mut carol = Client::new("carol", &url).await;
+    carol.join("rust").await;
+    carol.leave(DEFAULT_ROOM).await;
+
+    let text = "@jolene, @carol! meet @nobody";
+    let id = bob.send_msg(text).await;
+    let msg_id = bob.expect_receipt(id).await;
+
+    jolene
+        .expect_frame("broadcast with mentions", |f| {
+            matches!(f, ServerFrame::Broadcast { mentions, .. }
+                if *mentions == ["jolene", "carol"])
+        })
+        .await;
+    let mentioned = ServerFrame::Mentioned {
+        msg_id,
+        room: DEFAULT_ROOM.to_string(),
+        sender: "bob".to_string(),
+        msg: text.to_string(),
+    };
+    jolene.assert_frame(mentioned.clone()).await;
+    // not in the room, but mentioned
+    carol.assert_frame(mentioned.clone()).await;
+    carol
+        .assert_no_room_broadcast(DEFAULT_ROOM, "bob", text)
+        .await;
+
+    // only users an edit mentions for the first time hear about it
+    let mut dave = Client::new("dave", &url).await;
+    jolene.forget_incoming().await;
+    let text = "@jolene, @dave! meet @carol";
+    let id = bob.edit(msg_id, text).await;
+    bob.assert_frame(ServerFrame::Okay(id)).await;
+    let edited = ServerFrame::Edited {
+        room: DEFAULT_ROOM.to_string(),
+        msg_id,
+        msg: text.to_string(),
+    };
+    jolene.assert_frame(edited.clone()).await;
+    jolene
+        .assert_no_frame_matching("mention", |f| matches!(f, ServerFrame::Mentioned { .. }))
+        .await;
+    dave.assert_frame(edited.clone()).await;
+    dave.assert_frame(ServerFrame::Mentioned {
+        msg_id,
+        room: DEFAULT_ROOM.to_string(),
+        sender: "bob".to_string(),
+        msg: text.to_string(),
+    })
+    .await;
+    // edits only go to the members of the room
+    carol.assert_no_frame(edited).await;
+
+    bob.close().await;
+    jolene.close().await;
+    carol.close().await;
+    dave.close().await;
+}